@@ -3,81 +3,232 @@ extern crate log;
 
 use std::env;
 use std::sync::Arc;
+use std::time::Duration;
 use std::vec::Vec;
 
-use async_std::net::{SocketAddr, TcpListener};
+use async_std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
 use async_std::prelude::*;
 use async_std::task;
 
+use futures::channel::mpsc::{unbounded, UnboundedSender};
 use tide::Request;
 
 use stunnel::client::*;
-use stunnel::cryptor::Cryptor;
+use stunnel::compression::CompressionMetrics;
+use stunnel::cryptor::{CipherSuite, Cryptor};
+use stunnel::handshake;
 use stunnel::logger;
-use stunnel::proxy::{http, socks5, Proxy};
+use stunnel::proxy::dns::{self, DnsRequest};
+use stunnel::proxy::forward::{self, ForwardRequest, LocalForward};
+use stunnel::proxy::reverse::RemoteForward;
+use stunnel::proxy::{http, socks4, socks5, Proxy, ProxyUsers};
 use stunnel::ucp::UcpStreamMetrics;
 
+// Which proxies a listener speaks, a mixed listener tells them apart by the
+// first byte of a connection.
+#[derive(Clone, Copy)]
+enum Listener {
+    Socks,
+    Http,
+    Mixed,
+}
+
+enum Incoming {
+    Stream(Listener, TcpStream),
+    Dns(DnsRequest),
+    Listen(RemoteForward),
+    Forward(ForwardRequest),
+}
+
+async fn run_listener(
+    listener: TcpListener,
+    kind: Listener,
+    tx: UnboundedSender<(Listener, TcpStream)>,
+) {
+    let mut incoming = listener.incoming();
+
+    while let Some(stream) = incoming.next().await {
+        if let Ok(stream) = stream {
+            if tx.unbounded_send((kind, stream)).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+async fn run_proxy(
+    kind: Listener,
+    stream: TcpStream,
+    users: Arc<ProxyUsers>,
+    read_port: TunnelReadPort,
+    write_port: TunnelWritePort,
+) {
+    let mut ver = [0u8; 1];
+    if let Listener::Socks | Listener::Mixed = kind {
+        let _ = stream.peek(&mut ver).await;
+    }
+
+    match (kind, ver[0]) {
+        (Listener::Socks, socks4::VER) | (Listener::Mixed, socks4::VER) => {
+            let mut proxy = socks4::Socks4::new(users);
+            proxy.run_proxy_tunnel(stream, read_port, write_port).await;
+        }
+
+        (Listener::Socks, _) | (Listener::Mixed, socks5::VER) => {
+            let mut proxy = socks5::Socks5::new(users);
+            proxy.run_proxy_tunnel(stream, read_port, write_port).await;
+        }
+
+        _ => {
+            let mut proxy = http::Http::new(users);
+            proxy.run_proxy_tunnel(stream, read_port, write_port).await;
+        }
+    }
+}
+
 async fn run_proxy_tunnels(
     mut tunnels: Vec<Tunnel>,
-    socks5_addr: SocketAddr,
-    http_addr: SocketAddr,
+    listeners: Vec<(Listener, SocketAddr)>,
+    dns_addr: Option<SocketAddr>,
+    users: Arc<ProxyUsers>,
+    local_forwards: Vec<LocalForward>,
+    remote_forwards: Vec<RemoteForward>,
 ) {
     let mut index = 0;
-    let socks5_listener = TcpListener::bind(socks5_addr).await.unwrap();
-    let http_listener = TcpListener::bind(http_addr).await.unwrap();
-    let socks5_incoming = socks5_listener.incoming();
-    let http_incoming = http_listener.incoming();
-    let mut incoming = socks5_incoming.merge(http_incoming);
+    let (stream_tx, stream_rx) = unbounded();
+    for (kind, addr) in listeners {
+        let listener = TcpListener::bind(addr).await.unwrap();
+        task::spawn(run_listener(listener, kind, stream_tx.clone()));
+    }
 
-    while let Some(stream) = incoming.next().await {
-        match stream {
-            Ok(stream) => match stream.local_addr() {
-                Ok(addr) => {
-                    let tunnel: &mut Tunnel = tunnels.get_mut(index).unwrap();
-                    let (write_port, read_port) = tunnel.open_port().await;
-
-                    if addr.port() == http_addr.port() {
-                        let mut proxy = http::Http;
-                        task::spawn(async move {
-                            proxy.run_proxy_tunnel(stream, read_port, write_port).await;
-                        });
-                    } else {
-                        let mut proxy = socks5::Socks5::new();
-                        task::spawn(async move {
-                            proxy.run_proxy_tunnel(stream, read_port, write_port).await;
-                        });
-                    }
-
-                    index = (index + 1) % tunnels.len();
-                }
+    let (dns_tx, dns_rx) = unbounded();
+    if let Some(dns_addr) = dns_addr {
+        let socket = UdpSocket::bind(dns_addr).await.unwrap();
+        let listener = TcpListener::bind(dns_addr).await.unwrap();
+        task::spawn(dns::run_dns_forwarder(socket, listener, dns_tx));
+    }
+
+    let (forward_tx, forward_rx) = unbounded();
+    for forward in local_forwards {
+        let tx = forward_tx.clone();
+        if forward.is_udp() {
+            let socket = UdpSocket::bind(forward.local_addr()).await.unwrap();
+            task::spawn(forward::run_udp_forward(socket, forward, tx));
+        } else {
+            let listener = TcpListener::bind(forward.local_addr()).await.unwrap();
+            task::spawn(forward::run_tcp_forward(listener, forward, tx));
+        }
+    }
+
+    let (listen_tx, listen_rx) = unbounded();
+    for forward in remote_forwards {
+        let _ = listen_tx.unbounded_send(forward);
+    }
+
+    let mut incoming = stream_rx
+        .map(|(kind, stream)| Incoming::Stream(kind, stream))
+        .merge(dns_rx.map(Incoming::Dns))
+        .merge(forward_rx.map(Incoming::Forward))
+        .merge(listen_rx.map(Incoming::Listen));
+
+    while let Some(incoming) = incoming.next().await {
+        match incoming {
+            Incoming::Dns(request) => {
+                let tunnel: &mut Tunnel = tunnels.get_mut(index).unwrap();
+                let (write_port, read_port) = tunnel.open_port().await;
+                task::spawn(async move {
+                    request.forward(read_port, write_port).await;
+                });
+
+                index = (index + 1) % tunnels.len();
+            }
+
+            Incoming::Forward(request) => {
+                let tunnel: &mut Tunnel = tunnels.get_mut(index).unwrap();
+                let (write_port, read_port) = tunnel.open_port().await;
+                task::spawn(async move {
+                    request.run(read_port, write_port).await;
+                });
+
+                index = (index + 1) % tunnels.len();
+            }
+
+            // A forward listens again once its listener is gone.
+            Incoming::Listen(forward) => {
+                let tunnel: &mut Tunnel = tunnels.get_mut(index).unwrap();
+                let (write_port, read_port) = tunnel.open_port().await;
+                let listen_tx = listen_tx.clone();
+                task::spawn(async move {
+                    forward.run(read_port, write_port).await;
+                    let _ = listen_tx.unbounded_send(forward);
+                });
+
+                index = (index + 1) % tunnels.len();
+            }
 
-                Err(_) => {}
-            },
+            Incoming::Stream(kind, stream) => {
+                let tunnel: &mut Tunnel = tunnels.get_mut(index).unwrap();
+                let (write_port, read_port) = tunnel.open_port().await;
+                let users = users.clone();
+                task::spawn(async move {
+                    run_proxy(kind, stream, users, read_port, write_port).await;
+                });
 
-            Err(_) => {}
+                index = (index + 1) % tunnels.len();
+            }
         }
     }
 }
 
-async fn run_http_server(mut app: tide::Server<Arc<UcpStreamMetrics>>, addr: String) {
+fn compression_stats(metrics: &CompressionMetrics) -> String {
+    format!(
+        "compression_raw_bytes: {}\ncompression_wire_bytes: {}\ncompression_ratio: {:.2}\n",
+        metrics.get_raw_bytes(),
+        metrics.get_wire_bytes(),
+        metrics.get_ratio()
+    )
+}
+
+async fn run_http_server(
+    mut app: tide::Server<Arc<UcpStreamMetrics>>,
+    addr: String,
+    compression: Arc<CompressionMetrics>,
+) {
+    let ucp_compression = compression.clone();
+
     app.at("/").get(|_| async { Ok("Hello, world!") });
+    app.at("/compression").get(move |_| {
+        let compression = compression.clone();
+        async move { Ok(compression_stats(&compression)) }
+    });
     app.at("/ucp")
-        .get(|req: Request<Arc<UcpStreamMetrics>>| async move {
-            let metrics = req.state();
-            let send_queue = metrics.get_send_queue();
-            let recv_queue = metrics.get_recv_queue();
-            let send_buffer = metrics.get_send_buffer();
-            let una = metrics.get_una();
-            let rto = metrics.get_rto();
-            let srtt = metrics.get_srtt();
-            let rttvar = metrics.get_rttvar();
-            let rx_seq = metrics.get_rx_seq();
-
-            Ok(format!(
-                "send_queue: {}\nrecv_queue: {}\nsend_buffer: {}\nrto: {}\nsrtt: {}\n\
-                 rttvar: {}\nuna: {}\nrx_seq: {}",
-                send_queue, recv_queue, send_buffer, rto, srtt, rttvar, una, rx_seq
-            ))
+        .get(move |req: Request<Arc<UcpStreamMetrics>>| {
+            let compression = ucp_compression.clone();
+            async move {
+                let metrics = req.state();
+                let send_queue = metrics.get_send_queue();
+                let recv_queue = metrics.get_recv_queue();
+                let send_buffer = metrics.get_send_buffer();
+                let una = metrics.get_una();
+                let rto = metrics.get_rto();
+                let srtt = metrics.get_srtt();
+                let rttvar = metrics.get_rttvar();
+                let rx_seq = metrics.get_rx_seq();
+
+                Ok(format!(
+                    "send_queue: {}\nrecv_queue: {}\nsend_buffer: {}\nrto: {}\nsrtt: {}\n\
+                     rttvar: {}\nuna: {}\nrx_seq: {}\n{}",
+                    send_queue,
+                    recv_queue,
+                    send_buffer,
+                    rto,
+                    srtt,
+                    rttvar,
+                    una,
+                    rx_seq,
+                    compression_stats(&compression)
+                ))
+            }
         });
 
     let _ = app.listen(addr).await;
@@ -89,7 +240,7 @@ fn main() {
 
     let mut opts = getopts::Options::new();
     opts.reqopt("s", "server", "server address", "server-address");
-    opts.reqopt("k", "key", "secret key", "key");
+    opts.reqopt("k", "key", "secret passphrase", "key");
     opts.optopt(
         "c",
         "tcp-tunnel-count",
@@ -108,8 +259,63 @@ fn main() {
         "http proxy listen address",
         "http-proxy-address",
     );
+    opts.optopt(
+        "",
+        "mixed-proxy",
+        "listen address taking socks5, socks4 and http proxy requests",
+        "mixed-proxy-address",
+    );
+    opts.optopt(
+        "",
+        "dns-proxy",
+        "dns proxy listen address on udp and tcp, resolving through the tunnel",
+        "dns-proxy-address",
+    );
+    opts.optopt(
+        "",
+        "proxy-users",
+        "users file of the socks5 and http proxies, one \"username password\" per line",
+        "users-path",
+    );
+    opts.optmulti(
+        "L",
+        "local-forward",
+        "forward local-address to remote-host:remote-port, /udp appended for udp",
+        "local-address:remote-host:remote-port",
+    );
+    opts.optmulti(
+        "R",
+        "remote-forward",
+        "have the server listen on remote-address and forward to local-address",
+        "remote-address:local-address",
+    );
     opts.optopt("", "http", "http listen address", "http-address");
     opts.optopt("", "log", "log path", "log-path");
+    opts.optopt(
+        "",
+        "cipher",
+        "cipher suite: chacha20-poly1305 (default) or aes-256-gcm",
+        "cipher",
+    );
+    opts.optopt(
+        "",
+        "rekey-bytes",
+        "rekey after this many bytes, 0 to disable (default 1073741824)",
+        "bytes",
+    );
+    opts.optopt(
+        "",
+        "rekey-interval",
+        "rekey after this many seconds, 0 to disable (default 3600)",
+        "seconds",
+    );
+    opts.optopt(
+        "",
+        "padding",
+        "send padding frames of up to this percent of the data",
+        "percent",
+    );
+    opts.optflag("", "compress", "offer deflate compression of data");
     opts.optflag("", "enable-ucp", "enable ucp");
 
     let matches = match opts.parse(&args[1..]) {
@@ -125,22 +331,111 @@ fn main() {
     let key = matches.opt_str("k").unwrap().into_bytes();
     let log_path = matches.opt_str("log").unwrap_or(String::new());
     let enable_ucp = matches.opt_present("enable-ucp");
+    let compress = matches.opt_present("compress");
+    let cipher = matches.opt_str("cipher");
+    let padding = matches.opt_str("padding");
+    let rekey_bytes = matches.opt_str("rekey-bytes");
+    let rekey_interval = matches.opt_str("rekey-interval");
     let socks5_proxy_addr = matches
         .opt_str("socks5-proxy")
         .unwrap_or(String::from("127.0.0.1:1080"));
     let http_proxy_addr = matches
         .opt_str("http-proxy")
         .unwrap_or(String::from("127.0.0.1:8888"));
+    let mixed_proxy_addr = matches.opt_str("mixed-proxy");
+    let dns_proxy_addr = matches.opt_str("dns-proxy");
+    let users_path = matches.opt_str("proxy-users");
+    let local_forwards = matches.opt_strs("L");
+    let remote_forwards = matches.opt_strs("R");
     let http_addr = matches
         .opt_str("http")
         .unwrap_or(String::from("127.0.0.1:8080"));
-    let (min, max) = Cryptor::key_size_range();
 
-    if key.len() < min || key.len() > max {
-        println!("key length must in range [{}, {}]", min, max);
+    if key.is_empty() {
+        println!("key must not be empty");
         return;
     }
 
+    let suite = match cipher {
+        Some(name) => match CipherSuite::from_name(&name) {
+            Some(suite) => suite,
+            None => {
+                println!("unknown cipher suite: {}", name);
+                return;
+            }
+        },
+        None => CipherSuite::default(),
+    };
+
+    let rekey_bytes = match rekey_bytes.map_or(Ok(1 << 30), |s| s.parse::<u64>()) {
+        Ok(0) => None,
+        Ok(bytes) => Some(bytes),
+        Err(_) => {
+            println!("invalid rekey bytes");
+            return;
+        }
+    };
+
+    let rekey_interval = match rekey_interval.map_or(Ok(3600), |s| s.parse::<u64>()) {
+        Ok(0) => None,
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => {
+            println!("invalid rekey interval");
+            return;
+        }
+    };
+
+    let padding = match padding.map(|s| s.parse::<u32>()) {
+        None => None,
+        Some(Ok(percent)) => Some(percent),
+        Some(Err(_)) => {
+            println!("invalid padding percent");
+            return;
+        }
+    };
+
+    let mut users = ProxyUsers::new();
+    if let Some(path) = users_path {
+        match handshake::load_users(&path) {
+            Ok(list) => {
+                for (name, password) in list {
+                    users.add_user(name, password);
+                }
+            }
+            Err(err) => {
+                println!("load users from {} error: {}", path, err);
+                return;
+            }
+        }
+
+        if users.is_empty() {
+            println!("no users in {}", path);
+            return;
+        }
+    }
+
+    let mut local = Vec::new();
+    for forward in local_forwards {
+        match LocalForward::parse(&forward) {
+            Some(forward) => local.push(forward),
+            None => {
+                println!("invalid local forward: {}", forward);
+                return;
+            }
+        }
+    }
+
+    let mut remote = Vec::new();
+    for forward in remote_forwards {
+        match RemoteForward::parse(&forward) {
+            Some(forward) => remote.push(forward),
+            None => {
+                println!("invalid remote forward: {}", forward);
+                return;
+            }
+        }
+    }
+
     let count: u32 = match tunnel_count.parse() {
         Err(_) | Ok(0) => 1,
         Ok(count) => count,
@@ -149,25 +444,51 @@ fn main() {
     logger::init(log::Level::Info, log_path, 1, 2000000).unwrap();
     info!("starting up");
 
+    let compression_metrics = Arc::new(CompressionMetrics::new());
+    let config = Arc::new(TunnelConfig {
+        server_addr,
+        key: Cryptor::derive_key(&key),
+        suite,
+        rekey_bytes,
+        rekey_interval,
+        padding,
+        compress,
+        compression_metrics: compression_metrics.clone(),
+    });
+
     task::block_on(async move {
         let ucp_metrics = Arc::new(UcpStreamMetrics::new());
         let mut tunnels = Vec::new();
         let app = tide::with_state(ucp_metrics.clone());
 
         if enable_ucp {
-            let tunnel = UcpTunnel::new(0, server_addr.clone(), key.clone(), ucp_metrics);
+            let tunnel = UcpTunnel::new(0, config.clone(), ucp_metrics);
             tunnels.push(tunnel);
         } else {
             for i in 0..count {
-                let tunnel = TcpTunnel::new(i, server_addr.clone(), key.clone());
+                let tunnel = TcpTunnel::new(i, config.clone());
                 tunnels.push(tunnel);
             }
         }
 
-        let socks5_proxy_addr = socks5_proxy_addr.parse().unwrap();
-        let http_proxy_addr = http_proxy_addr.parse().unwrap();
-        let t = run_proxy_tunnels(tunnels, socks5_proxy_addr, http_proxy_addr);
-        let h = run_http_server(app, http_addr);
+        let mut listeners = vec![
+            (Listener::Socks, socks5_proxy_addr.parse().unwrap()),
+            (Listener::Http, http_proxy_addr.parse().unwrap()),
+        ];
+        if let Some(addr) = mixed_proxy_addr {
+            listeners.push((Listener::Mixed, addr.parse().unwrap()));
+        }
+
+        let dns_proxy_addr = dns_proxy_addr.map(|addr| addr.parse().unwrap());
+        let t = run_proxy_tunnels(
+            tunnels,
+            listeners,
+            dns_proxy_addr,
+            Arc::new(users),
+            local,
+            remote,
+        );
+        let h = run_http_server(app, http_addr, compression_metrics);
         t.join(h).await;
     });
 }
@@ -3,32 +3,36 @@ extern crate log;
 
 use std::env;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_std::net::TcpListener;
 use async_std::prelude::*;
 use async_std::task;
 
-use stunnel::cryptor::Cryptor;
+use stunnel::compression::CompressionMetrics;
+use stunnel::cryptor::{CipherSuite, Cryptor};
+use stunnel::dns::{Resolver, Upstream};
+use stunnel::handshake::{self, ReplayCache, UserTable};
 use stunnel::logger;
 use stunnel::server::*;
 use stunnel::ucp::{UcpListener, UcpListenerMetrics};
 
 use tide::Request;
 
-async fn run_ucp_server(mut listener: UcpListener, key: Vec<u8>) {
+async fn run_ucp_server(mut listener: UcpListener, config: Arc<TunnelConfig>) {
     loop {
         let stream = listener.incoming().await;
-        UcpTunnel::new(key.clone(), stream);
+        UcpTunnel::new(config.clone(), stream);
     }
 }
 
-async fn run_tcp_server(listener: TcpListener, key: Vec<u8>) {
+async fn run_tcp_server(listener: TcpListener, config: Arc<TunnelConfig>) {
     let mut incoming = listener.incoming();
 
     while let Some(stream) = incoming.next().await {
         match stream {
             Ok(stream) => {
-                TcpTunnel::new(key.clone(), stream);
+                TcpTunnel::new(config.clone(), stream);
             }
 
             Err(_) => {}
@@ -36,34 +40,87 @@ async fn run_tcp_server(listener: TcpListener, key: Vec<u8>) {
     }
 }
 
-async fn run_http_server(mut app: tide::Server<Arc<UcpListenerMetrics>>, addr: String) {
+fn compression_stats(metrics: &CompressionMetrics) -> String {
+    format!(
+        "compression_raw_bytes: {}\ncompression_wire_bytes: {}\ncompression_ratio: {:.2}\n",
+        metrics.get_raw_bytes(),
+        metrics.get_wire_bytes(),
+        metrics.get_ratio()
+    )
+}
+
+fn dns_stats(resolver: &Resolver) -> String {
+    let metrics = resolver.metrics();
+
+    format!(
+        "queries: {}\ncache_hits: {}\nhit_rate: {:.2}\nlookups: {}\nfailures: {}\n\
+         average_latency_ms: {:.2}\ncache_size: {}\n",
+        metrics.get_queries(),
+        metrics.get_hits(),
+        metrics.get_hit_rate(),
+        metrics.get_lookups(),
+        metrics.get_failures(),
+        metrics.get_average_latency_ms(),
+        resolver.cache_size()
+    )
+}
+
+async fn run_http_server(
+    mut app: tide::Server<Arc<UcpListenerMetrics>>,
+    addr: String,
+    compression: Arc<CompressionMetrics>,
+    resolver: Arc<Resolver>,
+) {
+    let ucp_compression = compression.clone();
+
     app.at("/").get(|_| async { Ok("Hello, world!") });
+    app.at("/dns").get(move |_| {
+        let resolver = resolver.clone();
+        async move { Ok(dns_stats(&resolver)) }
+    });
+    app.at("/compression").get(move |_| {
+        let compression = compression.clone();
+        async move { Ok(compression_stats(&compression)) }
+    });
     app.at("/ucp")
-        .get(|req: Request<Arc<UcpListenerMetrics>>| async move {
-            let metrics = req.state().get_metrics().await;
-            let mut result = String::new();
-
-            result = result + &format!("Total client: {}\n", metrics.len());
-
-            for (a, m) in metrics.iter() {
-                let send_queue = m.get_send_queue();
-                let recv_queue = m.get_recv_queue();
-                let send_buffer = m.get_send_buffer();
-                let una = m.get_una();
-                let rto = m.get_rto();
-                let srtt = m.get_srtt();
-                let rttvar = m.get_rttvar();
-                let rx_seq = m.get_rx_seq();
-
-                result = result
-                    + &format!(
-                        "remote_addr: {}\nsend_queue: {}\nrecv_queue: {}\n\
+        .get(move |req: Request<Arc<UcpListenerMetrics>>| {
+            let compression = ucp_compression.clone();
+            async move {
+                let metrics = req.state().get_metrics().await;
+                let mut result = compression_stats(&compression);
+
+                result = result + &format!("\nTotal client: {}\n", metrics.len());
+
+                for (a, m) in metrics.iter() {
+                    let send_queue = m.get_send_queue();
+                    let recv_queue = m.get_recv_queue();
+                    let send_buffer = m.get_send_buffer();
+                    let una = m.get_una();
+                    let rto = m.get_rto();
+                    let srtt = m.get_srtt();
+                    let rttvar = m.get_rttvar();
+                    let rx_seq = m.get_rx_seq();
+                    let identity = m.get_identity();
+
+                    result = result
+                        + &format!(
+                            "remote_addr: {}\nidentity: {}\nsend_queue: {}\nrecv_queue: {}\n\
                          send_buffer: {}\nrto: {}\nsrtt: {}\nrttvar: {}\nuna: {}\nrx_seq: {}\n\n",
-                        a, send_queue, recv_queue, send_buffer, rto, srtt, rttvar, una, rx_seq
-                    );
-            }
+                            a,
+                            identity,
+                            send_queue,
+                            recv_queue,
+                            send_buffer,
+                            rto,
+                            srtt,
+                            rttvar,
+                            una,
+                            rx_seq
+                        );
+                }
 
-            Ok(result)
+                Ok(result)
+            }
         });
 
     let _ = app.listen(addr).await;
@@ -75,9 +132,45 @@ fn main() {
 
     let mut opts = getopts::Options::new();
     opts.reqopt("l", "listen", "listen address", "listen-address");
-    opts.reqopt("k", "key", "secret key", "key");
+    opts.optopt("k", "key", "secret passphrase", "key");
+    opts.optopt(
+        "",
+        "users",
+        "users file, one \"user-id passphrase\" per line",
+        "users-path",
+    );
     opts.optopt("", "log", "log path", "log-path");
     opts.optopt("", "http", "http address", "http-address");
+    opts.optopt(
+        "",
+        "padding",
+        "send padding frames of up to this percent of the data",
+        "percent",
+    );
+    opts.optflag("", "no-compress", "refuse compression offered by clients");
+    opts.optflag(
+        "",
+        "allow-remote-forward",
+        "let clients have the server listen and forward connections to them",
+    );
+    opts.optmulti(
+        "",
+        "dns",
+        "upstream dns server, udp://ip[:port] or tcp://ip[:port], the system resolver if none",
+        "dns-server",
+    );
+    opts.optopt(
+        "",
+        "dns-timeout",
+        "dns resolution timeout in milliseconds (default 5000)",
+        "milliseconds",
+    );
+    opts.optopt(
+        "",
+        "cipher",
+        "cipher suite: chacha20-poly1305 (default) or aes-256-gcm",
+        "cipher",
+    );
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -88,30 +181,129 @@ fn main() {
     };
 
     let listen_addr = matches.opt_str("l").unwrap();
-    let key = matches.opt_str("k").unwrap().into_bytes();
+    let key = matches.opt_str("k");
+    let users_path = matches.opt_str("users");
     let log_path = matches.opt_str("log").unwrap_or(String::new());
+    let cipher = matches.opt_str("cipher");
+    let padding = matches.opt_str("padding");
+    let compress = !matches.opt_present("no-compress");
+    let remote_forward = matches.opt_present("allow-remote-forward");
+    let dns_servers = matches.opt_strs("dns");
+    let dns_timeout = matches.opt_str("dns-timeout");
     let http_addr = matches
         .opt_str("http")
         .unwrap_or(String::from("127.0.0.1:8080"));
-    let (min, max) = Cryptor::key_size_range();
 
-    if key.len() < min || key.len() > max {
-        println!("key length must in range [{}, {}]", min, max);
+    let mut passphrases = Vec::new();
+
+    if let Some(key) = key {
+        passphrases.push((String::from("default"), key));
+    }
+
+    if let Some(path) = users_path {
+        match handshake::load_users(&path) {
+            Ok(mut users) => {
+                // -k takes the id default, which the file cannot have again.
+                if let Some((id, _)) = users
+                    .iter()
+                    .find(|(id, _)| passphrases.iter().any(|(other, _)| other == id))
+                {
+                    println!("load users from {} error: duplicate user id {}", path, id);
+                    return;
+                }
+                passphrases.append(&mut users);
+            }
+            Err(err) => {
+                println!("load users from {} error: {}", path, err);
+                return;
+            }
+        }
+    }
+
+    if passphrases.is_empty() {
+        println!("{}", opts.short_usage(&program));
+        return;
+    }
+
+    if passphrases
+        .iter()
+        .any(|(_, passphrase)| passphrase.is_empty())
+    {
+        println!("key must not be empty");
         return;
     }
 
+    let padding = match padding.map(|s| s.parse::<u32>()) {
+        None => None,
+        Some(Ok(percent)) => Some(percent),
+        Some(Err(_)) => {
+            println!("invalid padding percent");
+            return;
+        }
+    };
+
+    let suite = match cipher {
+        Some(name) => match CipherSuite::from_name(&name) {
+            Some(suite) => suite,
+            None => {
+                println!("unknown cipher suite: {}", name);
+                return;
+            }
+        },
+        None => CipherSuite::default(),
+    };
+
+    let mut upstreams = Vec::new();
+    for server in dns_servers {
+        match Upstream::parse(&server) {
+            Some(upstream) => upstreams.push(upstream),
+            None => {
+                println!("invalid dns server: {}", server);
+                return;
+            }
+        }
+    }
+
+    let dns_timeout = match dns_timeout.map_or(Ok(5000), |s| s.parse::<u64>()) {
+        Ok(ms) if ms > 0 => Duration::from_millis(ms),
+        _ => {
+            println!("invalid dns timeout");
+            return;
+        }
+    };
+
     logger::init(log::Level::Info, log_path, 1, 2000000).unwrap();
     info!("starting up");
 
+    let mut users = UserTable::new();
+    for (id, passphrase) in passphrases {
+        users.add_user(id, Cryptor::derive_key(passphrase.as_bytes()));
+    }
+
+    info!("loaded {} users", users.len());
+
     task::block_on(async move {
         let metrics = Arc::new(UcpListenerMetrics::new());
         let ucp_listener = UcpListener::bind(&listen_addr, metrics.clone()).await;
         let tcp_listener = TcpListener::bind(&listen_addr).await.unwrap();
         let http_app = tide::with_state(metrics);
+        let compression_metrics = Arc::new(CompressionMetrics::new());
+        let resolver = Arc::new(Resolver::new(upstreams, dns_timeout));
+
+        let config = Arc::new(TunnelConfig {
+            users,
+            suite,
+            replay_cache: ReplayCache::new(),
+            padding,
+            compress,
+            compression_metrics: compression_metrics.clone(),
+            resolver: resolver.clone(),
+            remote_forward,
+        });
 
-        let u = run_ucp_server(ucp_listener, key.clone());
-        let t = run_tcp_server(tcp_listener, key.clone());
-        let h = run_http_server(http_app, http_addr);
+        let u = run_ucp_server(ucp_listener, config.clone());
+        let t = run_tcp_server(tcp_listener, config);
+        let h = run_http_server(http_app, http_addr, compression_metrics, resolver);
         u.join(t).join(h).await;
     });
 }
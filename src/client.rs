@@ -9,42 +9,111 @@ use async_std::net::TcpStream;
 use async_std::prelude::*;
 use async_std::task;
 
-use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::channel::mpsc::{unbounded, Sender, UnboundedReceiver, UnboundedSender};
 use futures::sink::SinkExt;
 
+use super::compression::CompressionMetrics;
 use super::cryptor::*;
+use super::handshake::*;
 use super::protocol::*;
+use super::scheduler::{Priority, Schedule, Scheduler};
 use super::timer;
 use super::ucp::{UcpStream, UcpStreamMetrics};
 use super::util::*;
 
+pub use super::protocol::ConnectError;
+
 #[derive(Clone)]
 enum TunnelMsg {
-    CSOpenPort(u32, Sender<TunnelPortMsg>),
+    CSOpenPort(u32, UnboundedSender<TunnelPortMsg>, UnboundedSender<u64>),
     CSConnect(u32, Vec<u8>),
     CSConnectDN(u32, Vec<u8>, u16),
     CSUdpAssociate(u32, Vec<u8>),
+    CSResolve(u32, Vec<u8>),
+    CSListen(u32, Vec<u8>),
+    CSBind(u32, Vec<u8>),
     CSShutdownWrite(u32),
     CSClosePort(u32),
     CSData(u32, Vec<u8>),
+    CSWindowUpdate(u32, u32),
 
     SCHeartbeat,
+    SCOpenPort(u32, u32, Vec<u8>),
     SCClosePort(u32),
     SCShutdownWrite(u32),
     SCConnectOk(u32, Vec<u8>),
+    SCConnectErr(u32, ConnectError),
     SCData(u32, Vec<u8>),
+    SCWindowUpdate(u32, u32),
 
     Heartbeat,
     TunnelPortHalfDrop(u32),
 }
 
+impl Schedule for TunnelMsg {
+    fn priority(&self) -> Priority {
+        match *self {
+            TunnelMsg::CSData(id, _) => Priority::Data(id),
+            TunnelMsg::CSShutdownWrite(id)
+            | TunnelMsg::CSClosePort(id)
+            | TunnelMsg::TunnelPortHalfDrop(id) => Priority::Ordered(id),
+            _ => Priority::Control,
+        }
+    }
+
+    fn split(self, size: usize) -> Vec<TunnelMsg> {
+        match self {
+            TunnelMsg::CSData(id, buf) if buf.len() > size => buf
+                .chunks(size)
+                .map(|chunk| TunnelMsg::CSData(id, chunk.to_vec()))
+                .collect(),
+            msg => vec![msg],
+        }
+    }
+}
+
 pub enum TunnelPortMsg {
+    // A port the server opened for a connection to a listening port.
+    Accept(TunnelWritePort, TunnelReadPort),
     ConnectOk(Vec<u8>),
+    ConnectErr(ConnectError),
     Data(Vec<u8>),
     ShutdownWrite,
     ClosePort,
 }
 
+pub struct TunnelConfig {
+    pub server_addr: String,
+    pub key: Vec<u8>,
+    pub suite: CipherSuite,
+    // Rekey after this many bytes or this long on one key, whichever is first.
+    pub rekey_bytes: Option<u64>,
+    pub rekey_interval: Option<Duration>,
+    // Padding budget in percent of the data sent, also jitters heartbeats.
+    pub padding: Option<u32>,
+    pub compress: bool,
+    pub compression_metrics: Arc<CompressionMetrics>,
+}
+
+impl TunnelConfig {
+    fn capabilities(&self) -> u32 {
+        if self.compress {
+            capability::SUPPORTED | capability::COMPRESSION
+        } else {
+            capability::SUPPORTED
+        }
+    }
+
+    // Where to count DATA when the session compresses it.
+    fn compression(&self, session: &Session) -> Option<&CompressionMetrics> {
+        if session.supports(capability::COMPRESSION) {
+            Some(&self.compression_metrics)
+        } else {
+            None
+        }
+    }
+}
+
 pub struct Tunnel {
     id: u32,
     senders: SubSenders<TunnelMsg>,
@@ -57,12 +126,15 @@ pub struct UcpTunnel;
 pub struct TunnelWritePort {
     id: u32,
     tx: Sender<TunnelMsg>,
+    credit: u64,
+    credit_rx: UnboundedReceiver<u64>,
 }
 
 pub struct TunnelReadPort {
     id: u32,
     tx: Sender<TunnelMsg>,
-    rx: Option<Receiver<TunnelPortMsg>>,
+    rx: Option<UnboundedReceiver<TunnelPortMsg>>,
+    consumed: u32,
 }
 
 impl Tunnel {
@@ -70,8 +142,12 @@ impl Tunnel {
         let id = self.id;
         self.id += 1;
 
-        let (tx, rx) = channel(1000);
-        let _ = self.main_sender.send(TunnelMsg::CSOpenPort(id, tx)).await;
+        let (tx, rx) = unbounded();
+        let (credit_tx, credit_rx) = unbounded();
+        let _ = self
+            .main_sender
+            .send(TunnelMsg::CSOpenPort(id, tx, credit_tx))
+            .await;
 
         let sender = self.senders.get_one_sender();
 
@@ -79,35 +155,31 @@ impl Tunnel {
             TunnelWritePort {
                 id: id,
                 tx: sender.clone(),
+                credit: 0,
+                credit_rx,
             },
             TunnelReadPort {
                 id: id,
                 tx: sender.clone(),
                 rx: Some(rx),
+                consumed: 0,
             },
         )
     }
 }
 
 impl TcpTunnel {
-    pub fn new(tid: u32, server_addr: String, key: Vec<u8>) -> Tunnel {
+    pub fn new(tid: u32, config: Arc<TunnelConfig>) -> Tunnel {
         let (main_sender, sub_senders, receivers) = channel_bus(10, 1000);
         let core_sender = main_sender.clone();
 
         task::spawn(async move {
-            let duration = Duration::from_millis(HEARTBEAT_INTERVAL_MS);
-            let timer_stream = timer::interval(duration, TunnelMsg::Heartbeat);
-            let mut msg_stream = timer_stream.merge(receivers);
+            let timer_stream = heartbeat_timer(&config);
+            let mut msg_stream = Scheduler::new(timer_stream.merge(receivers));
 
             loop {
-                tcp_tunnel_core_task(
-                    tid,
-                    server_addr.clone(),
-                    key.clone(),
-                    &mut msg_stream,
-                    core_sender.clone(),
-                )
-                .await;
+                tcp_tunnel_core_task(tid, config.clone(), &mut msg_stream, core_sender.clone())
+                    .await;
             }
         });
 
@@ -120,25 +192,18 @@ impl TcpTunnel {
 }
 
 impl UcpTunnel {
-    pub fn new(
-        tid: u32,
-        server_addr: String,
-        key: Vec<u8>,
-        ucp_metrics: Arc<UcpStreamMetrics>,
-    ) -> Tunnel {
+    pub fn new(tid: u32, config: Arc<TunnelConfig>, ucp_metrics: Arc<UcpStreamMetrics>) -> Tunnel {
         let (main_sender, sub_senders, receivers) = channel_bus(10, 1000);
         let core_sender = main_sender.clone();
 
         task::spawn(async move {
-            let duration = Duration::from_millis(HEARTBEAT_INTERVAL_MS);
-            let timer_stream = timer::interval(duration, TunnelMsg::Heartbeat);
-            let mut msg_stream = timer_stream.merge(receivers);
+            let timer_stream = heartbeat_timer(&config);
+            let mut msg_stream = Scheduler::new(timer_stream.merge(receivers));
 
             loop {
                 ucp_tunnel_core_task(
                     tid,
-                    server_addr.clone(),
-                    key.clone(),
+                    config.clone(),
                     &mut msg_stream,
                     core_sender.clone(),
                     ucp_metrics.clone(),
@@ -155,9 +220,39 @@ impl UcpTunnel {
     }
 }
 
+fn heartbeat_timer(config: &TunnelConfig) -> timer::Interval<TunnelMsg> {
+    let duration = Duration::from_millis(HEARTBEAT_INTERVAL_MS);
+    let jitter = match config.padding {
+        Some(_) => duration / 2,
+        None => Duration::from_millis(0),
+    };
+
+    timer::jittered_interval(duration, jitter, TunnelMsg::Heartbeat)
+}
+
 impl TunnelWritePort {
-    pub async fn write(&mut self, buf: Vec<u8>) {
-        let _ = self.tx.send(TunnelMsg::CSData(self.id, buf)).await;
+    // Sends as much as the credit allows and waits for the server to grant
+    // more for the rest, so a slow consumer on the other side stalls only
+    // this port, and a buffer larger than the window still goes out.
+    pub async fn write(&mut self, mut buf: Vec<u8>) {
+        loop {
+            if self.credit == 0 && !buf.is_empty() {
+                match self.credit_rx.next().await {
+                    Some(credit) => self.credit = self.credit.saturating_add(credit),
+                    None => return,
+                }
+                continue;
+            }
+
+            let rest = buf.split_off(self.credit.min(buf.len() as u64) as usize);
+            self.credit -= buf.len() as u64;
+            let _ = self.tx.send(TunnelMsg::CSData(self.id, buf)).await;
+
+            if rest.is_empty() {
+                return;
+            }
+            buf = rest;
+        }
     }
 
     pub async fn connect(&mut self, buf: Vec<u8>) {
@@ -175,6 +270,28 @@ impl TunnelWritePort {
         let _ = self.tx.send(TunnelMsg::CSUdpAssociate(self.id, buf)).await;
     }
 
+    // The server answers with ConnectOk carrying the addresses of the name
+    // separated by commas, or with ConnectErr.
+    pub async fn resolve(&mut self, domain_name: Vec<u8>) {
+        let _ = self
+            .tx
+            .send(TunnelMsg::CSResolve(self.id, domain_name))
+            .await;
+    }
+
+    // The server answers with ConnectOk carrying the address it listens on,
+    // then hands every connection to the port as Accept.
+    pub async fn listen(&mut self, buf: Vec<u8>) {
+        let _ = self.tx.send(TunnelMsg::CSListen(self.id, buf)).await;
+    }
+
+    // The server answers with ConnectOk carrying the address it listens on
+    // for the peer at `buf`, and with ConnectOk carrying the address of the
+    // peer once it connects.
+    pub async fn bind(&mut self, buf: Vec<u8>) {
+        let _ = self.tx.send(TunnelMsg::CSBind(self.id, buf)).await;
+    }
+
     pub async fn shutdown_write(&mut self) {
         let _ = self.tx.send(TunnelMsg::CSShutdownWrite(self.id)).await;
     }
@@ -194,14 +311,25 @@ impl TunnelReadPort {
     }
 
     pub async fn read(&mut self) -> TunnelPortMsg {
-        match self.rx {
+        let msg = match self.rx {
             Some(ref mut receiver) => match receiver.next().await {
                 Some(msg) => msg,
                 None => TunnelPortMsg::ClosePort,
             },
 
             None => TunnelPortMsg::ClosePort,
+        };
+
+        if let TunnelPortMsg::Data(ref buf) = msg {
+            self.consumed += buf.len() as u32;
+            if self.consumed >= WINDOW_UPDATE_THRESHOLD {
+                let update = TunnelMsg::CSWindowUpdate(self.id, self.consumed);
+                let _ = self.tx.send(update).await;
+                self.consumed = 0;
+            }
         }
+
+        msg
     }
 
     pub async fn close(&mut self) {
@@ -216,31 +344,114 @@ impl TunnelReadPort {
 struct Port {
     address: String,
     count: u32,
-    tx: Sender<TunnelPortMsg>,
+    tx: UnboundedSender<TunnelPortMsg>,
+    credit_tx: UnboundedSender<u64>,
+    // Bytes the server may still send before it needs a window update.
+    window: u64,
 }
 
-struct PortHub(u32, HashMap<u32, Port>);
+// The third field tells whether flow control was negotiated.
+struct PortHub(u32, HashMap<u32, Port>, bool);
 
 impl PortHub {
-    fn new(id: u32) -> Self {
-        PortHub(id, HashMap::new())
+    fn new(id: u32, flow_control: bool) -> Self {
+        PortHub(id, HashMap::new(), flow_control)
     }
 
     fn get_id(&self) -> u32 {
         self.0
     }
 
-    fn add_port(&mut self, id: u32, tx: Sender<TunnelPortMsg>) {
+    fn add_port(
+        &mut self,
+        id: u32,
+        tx: UnboundedSender<TunnelPortMsg>,
+        credit_tx: UnboundedSender<u64>,
+    ) {
+        // Without flow control the port may send as much as it likes.
+        let credit = if self.2 {
+            INITIAL_WINDOW as u64
+        } else {
+            u64::MAX
+        };
+        let _ = credit_tx.unbounded_send(credit);
+
         self.1.insert(
             id,
             Port {
                 address: String::new(),
                 count: 2,
-                tx: tx,
+                tx,
+                credit_tx,
+                window: INITIAL_WINDOW as u64,
             },
         );
     }
 
+    // Registers a port the server opened and hands it to its listening port,
+    // returns false when that is gone.
+    fn remote_open_port(
+        &mut self,
+        id: u32,
+        listen_id: u32,
+        address: String,
+        tx: &Sender<TunnelMsg>,
+    ) -> bool {
+        let self_id = self.get_id();
+        let listener = match self.1.get(&listen_id) {
+            Some(value) => value.tx.clone(),
+            None => {
+                info!("{}.{}: open port of unknown listener", self_id, id);
+                return false;
+            }
+        };
+
+        let (port_tx, rx) = unbounded();
+        let (credit_tx, credit_rx) = unbounded();
+        self.add_port(id, port_tx, credit_tx);
+
+        info!("{}.{}: accept {} on {}", self_id, id, address, listen_id);
+        self.update_address(id, address);
+
+        let write_port = TunnelWritePort {
+            id,
+            tx: tx.clone(),
+            credit: 0,
+            credit_rx,
+        };
+        let read_port = TunnelReadPort {
+            id,
+            tx: tx.clone(),
+            rx: Some(rx),
+            consumed: 0,
+        };
+
+        let accept = TunnelPortMsg::Accept(write_port, read_port);
+        if listener.unbounded_send(accept).is_err() {
+            self.1.remove(&id);
+            return false;
+        }
+
+        true
+    }
+
+    fn add_credit(&mut self, id: u32, credit: u32) {
+        if let Some(value) = self.1.get(&id) {
+            let _ = value.credit_tx.unbounded_send(credit as u64);
+        }
+    }
+
+    // Returns false when the port is gone and no update should be sent.
+    fn grant_window(&mut self, id: u32, credit: u32) -> bool {
+        match self.1.get_mut(&id) {
+            Some(value) if self.2 => {
+                value.window += credit as u64;
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn update_address(&mut self, id: u32, address: String) {
         if let Some(value) = self.1.get_mut(&id) {
             value.address = address;
@@ -343,15 +554,48 @@ impl PortHub {
         }
     }
 
-    async fn server_send_data(&mut self, id: u32, buf: Vec<u8>) {
+    async fn connect_err(&mut self, id: u32, err: ConnectError) {
+        match self.1.get(&id) {
+            Some(value) => {
+                info!(
+                    "{}.{}: connect {} error: {}",
+                    self.get_id(),
+                    id,
+                    value.address,
+                    err
+                );
+                self.try_send_msg(id, TunnelPortMsg::ConnectErr(err)).await;
+            }
+
+            None => {
+                info!("{}.{}: connect unknown server error", self.get_id(), id);
+            }
+        }
+    }
+
+    // Returns false when the server sent more than the port's window.
+    async fn server_send_data(&mut self, id: u32, buf: Vec<u8>) -> bool {
+        let flow_control = self.2;
+
+        if let Some(value) = self.1.get_mut(&id) {
+            if flow_control {
+                if buf.len() as u64 > value.window {
+                    return false;
+                }
+
+                value.window -= buf.len() as u64;
+            }
+        }
+
         self.try_send_msg(id, TunnelPortMsg::Data(buf)).await;
+        true
     }
 
     async fn try_send_msg(&mut self, id: u32, msg: TunnelPortMsg) {
         let self_id = self.get_id();
 
         if let Some(value) = self.1.get_mut(&id) {
-            match value.tx.send(msg).await {
+            match value.tx.unbounded_send(msg) {
                 Ok(_) => {}
                 Err(err) => {
                     error!(
@@ -367,12 +611,11 @@ impl PortHub {
 
 async fn tcp_tunnel_core_task<S: Stream<Item = TunnelMsg> + Unpin>(
     tid: u32,
-    server_addr: String,
-    key: Vec<u8>,
+    config: Arc<TunnelConfig>,
     msg_stream: &mut S,
     core_tx: Sender<TunnelMsg>,
 ) {
-    let stream = match TcpStream::connect(&server_addr).await {
+    let stream = match TcpStream::connect(&config.server_addr).await {
         Ok(stream) => stream,
 
         Err(_) => {
@@ -381,14 +624,45 @@ async fn tcp_tunnel_core_task<S: Stream<Item = TunnelMsg> + Unpin>(
         }
     };
 
-    let mut port_hub = PortHub::new(tid);
     let (reader, writer) = &mut (&stream, &stream);
+    let (session, encryptor, decryptor) = match client_handshake(
+        &config.key,
+        config.suite,
+        config.capabilities(),
+        reader,
+        writer,
+    )
+    .await
+    {
+        Ok(handshake) => handshake,
+
+        Err(err) => {
+            error!("TCP tunnel {} handshake error: {}", tid, err);
+            let _ = stream.shutdown(Shutdown::Both);
+            task::sleep(Duration::from_millis(1000)).await;
+            return;
+        }
+    };
+
+    let mut port_hub = PortHub::new(tid, session.supports(capability::FLOW_CONTROL));
+
+    let port_tx = core_tx.clone();
     let r = async {
-        let _ = process_tunnel_read(key.clone(), core_tx, reader).await;
+        let compression = config.compression(&session);
+        let _ = process_tunnel_read(decryptor, compression, core_tx, reader).await;
         let _ = stream.shutdown(Shutdown::Both);
     };
     let w = async {
-        let _ = process_tunnel_write(key.clone(), msg_stream, &mut port_hub, writer).await;
+        let _ = process_tunnel_write(
+            &config,
+            session,
+            encryptor,
+            msg_stream,
+            &port_tx,
+            &mut port_hub,
+            writer,
+        )
+        .await;
         let _ = stream.shutdown(Shutdown::Both);
     };
     let _ = r.join(w).await;
@@ -399,22 +673,52 @@ async fn tcp_tunnel_core_task<S: Stream<Item = TunnelMsg> + Unpin>(
 
 async fn ucp_tunnel_core_task<S: Stream<Item = TunnelMsg> + Unpin>(
     tid: u32,
-    server_addr: String,
-    key: Vec<u8>,
+    config: Arc<TunnelConfig>,
     msg_stream: &mut S,
     core_tx: Sender<TunnelMsg>,
     ucp_metrics: Arc<UcpStreamMetrics>,
 ) {
-    let stream = UcpStream::connect(&server_addr, ucp_metrics).await;
+    let stream = UcpStream::connect(&config.server_addr, ucp_metrics).await;
 
-    let mut port_hub = PortHub::new(tid);
     let (reader, writer) = &mut (&stream, &stream);
+    let (session, encryptor, decryptor) = match client_handshake(
+        &config.key,
+        config.suite,
+        config.capabilities(),
+        reader,
+        writer,
+    )
+    .await
+    {
+        Ok(handshake) => handshake,
+
+        Err(err) => {
+            error!("UCP tunnel {} handshake error: {}", tid, err);
+            stream.shutdown();
+            task::sleep(Duration::from_millis(1000)).await;
+            return;
+        }
+    };
+
+    let mut port_hub = PortHub::new(tid, session.supports(capability::FLOW_CONTROL));
+
+    let port_tx = core_tx.clone();
     let r = async {
-        let _ = process_tunnel_read(key.clone(), core_tx, reader).await;
+        let compression = config.compression(&session);
+        let _ = process_tunnel_read(decryptor, compression, core_tx, reader).await;
         stream.shutdown();
     };
     let w = async {
-        let _ = process_tunnel_write(key.clone(), msg_stream, &mut port_hub, writer).await;
+        let _ = process_tunnel_write(
+            &config,
+            session,
+            encryptor,
+            msg_stream,
+            &port_tx,
+            &mut port_hub,
+            writer,
+        )
+        .await;
         stream.shutdown();
     };
     let _ = r.join(w).await;
@@ -424,57 +728,72 @@ async fn ucp_tunnel_core_task<S: Stream<Item = TunnelMsg> + Unpin>(
 }
 
 async fn process_tunnel_read<R: Read + Unpin>(
-    key: Vec<u8>,
+    mut decryptor: Cryptor,
+    compression: Option<&CompressionMetrics>,
     mut core_tx: Sender<TunnelMsg>,
     stream: &mut R,
 ) -> std::io::Result<()> {
-    let mut ctr = vec![0; CTR_SIZE];
-    stream.read_exact(&mut ctr).await?;
-
-    let mut decryptor = Cryptor::with_ctr(&key, ctr);
-
     loop {
-        let mut op = [0u8; 1];
-        stream.read_exact(&mut op).await?;
-        let op = op[0];
+        let frame = match read_sc_frame(stream, &mut decryptor).await {
+            Ok(frame) => frame,
+            Err(FrameError::Io(err)) => return Err(err),
+            Err(err) => {
+                error!("Tunnel recv frame error: {}", err);
+                return Err(err.into());
+            }
+        };
 
-        if op == sc::HEARTBEAT_RSP {
-            let _ = core_tx.send(TunnelMsg::SCHeartbeat).await;
-            continue;
-        }
+        match frame {
+            Frame::HeartbeatRsp => {
+                let _ = core_tx.send(TunnelMsg::SCHeartbeat).await;
+            }
 
-        let mut id = [0u8; 4];
-        stream.read_exact(&mut id).await?;
-        let id = u32::from_be(unsafe { *(id.as_ptr() as *const u32) });
+            Frame::RemoteOpenPort(id, listen_id, peer) => {
+                let msg = TunnelMsg::SCOpenPort(id, listen_id, peer);
+                let _ = core_tx.send(msg).await;
+            }
 
-        match op {
-            sc::CLOSE_PORT => {
+            Frame::ClosePort(id) => {
                 let _ = core_tx.send(TunnelMsg::SCClosePort(id)).await;
             }
 
-            sc::SHUTDOWN_WRITE => {
+            Frame::ShutdownWrite(id) => {
                 let _ = core_tx.send(TunnelMsg::SCShutdownWrite(id)).await;
             }
 
-            sc::CONNECT_OK | sc::DATA => {
-                let mut len = [0u8; 4];
-                stream.read_exact(&mut len).await?;
-                let len = u32::from_be(unsafe { *(len.as_ptr() as *const u32) });
+            Frame::Rekey => {
+                decryptor.rekey();
+            }
+
+            Frame::Padding(_) => {}
 
-                let mut buf = vec![0; len as usize];
-                stream.read_exact(&mut buf).await?;
+            Frame::ConnectOk(id, data) => {
+                let _ = core_tx.send(TunnelMsg::SCConnectOk(id, data)).await;
+            }
+
+            Frame::ConnectErr(id, err) => {
+                let _ = core_tx.send(TunnelMsg::SCConnectErr(id, err)).await;
+            }
 
-                let data = decryptor.decrypt(&buf);
+            frame @ Frame::Data(..) | frame @ Frame::CompressedData(..) => {
+                match data_payload(frame, compression) {
+                    Ok((id, data)) => {
+                        let _ = core_tx.send(TunnelMsg::SCData(id, data)).await;
+                    }
 
-                if op == sc::CONNECT_OK {
-                    let _ = core_tx.send(TunnelMsg::SCConnectOk(id, data)).await;
-                } else {
-                    let _ = core_tx.send(TunnelMsg::SCData(id, data)).await;
+                    Err(err) => {
+                        error!("Tunnel recv frame error: {}", err);
+                        return Err(err.into());
+                    }
                 }
             }
 
-            _ => {
-                error!("Tunnel recv unknow operation: {}, id: {}", op, id);
+            Frame::WindowUpdate(id, credit) => {
+                let _ = core_tx.send(TunnelMsg::SCWindowUpdate(id, credit)).await;
+            }
+
+            frame => {
+                error!("Tunnel recv unexpected frame: {:?}", frame);
                 break;
             }
         }
@@ -484,16 +803,21 @@ async fn process_tunnel_read<R: Read + Unpin>(
 }
 
 async fn process_tunnel_write<W: Write + Unpin, S: Stream<Item = TunnelMsg> + Unpin>(
-    key: Vec<u8>,
+    config: &TunnelConfig,
+    session: Session,
+    mut encryptor: Cryptor,
     msg_stream: &mut S,
+    core_tx: &Sender<TunnelMsg>,
     port_hub: &mut PortHub,
     stream: &mut W,
 ) -> std::io::Result<()> {
-    let mut encryptor = Cryptor::new(&key);
     let mut alive_time = Instant::now();
-
-    stream.write_all(encryptor.ctr_as_slice()).await?;
-    stream.write_all(&encryptor.encrypt(&VERIFY_DATA)).await?;
+    let mut recv_bytes = 0u64;
+    let mut padding = config
+        .padding
+        .filter(|_| session.supports(capability::PADDING))
+        .map(Padding::new);
+    let rekey = session.supports(capability::REKEY);
 
     loop {
         match msg_stream.next().await {
@@ -505,11 +829,54 @@ async fn process_tunnel_write<W: Write + Unpin, S: Stream<Item = TunnelMsg> + Un
                     break;
                 }
 
-                stream.write_all(&pack_cs_heartbeat_msg()).await?;
+                let packed_buffer = pack_cs_frame(&Frame::Heartbeat, &mut encryptor)?;
+                stream.write_all(&packed_buffer).await?;
+            }
+
+            // Servers without RESOLVE would drop the tunnel on it.
+            Some(TunnelMsg::CSResolve(id, _)) if !session.supports(capability::RESOLVE) => {
+                port_hub.connect_err(id, ConnectError::General).await;
+            }
+
+            Some(TunnelMsg::CSListen(id, _)) if !session.supports(capability::REMOTE_FORWARD) => {
+                port_hub.connect_err(id, ConnectError::General).await;
+            }
+
+            Some(TunnelMsg::CSBind(id, _)) if !session.supports(capability::BIND) => {
+                port_hub.connect_err(id, ConnectError::General).await;
+            }
+
+            // Ports the server opens are fed by the core like the ports of
+            // the tunnel.
+            Some(TunnelMsg::SCOpenPort(id, listen_id, peer)) => {
+                alive_time = Instant::now();
+                let address = String::from_utf8(peer).unwrap_or_default();
+                if !port_hub.remote_open_port(id, listen_id, address, core_tx) {
+                    let packed_buffer = pack_cs_frame(&Frame::ClosePort(id), &mut encryptor)?;
+                    stream.write_all(&packed_buffer).await?;
+                }
             }
 
             Some(msg) => {
-                process_tunnel_msg(msg, &mut alive_time, port_hub, &mut encryptor, stream).await?;
+                match msg {
+                    TunnelMsg::SCData(_, ref buf) => recv_bytes += buf.len() as u64,
+                    TunnelMsg::CSData(_, ref buf) => {
+                        if let Some(ref mut padding) = padding {
+                            padding.add_data(buf.len());
+                        }
+                    }
+                    _ => {}
+                }
+
+                process_tunnel_msg(
+                    msg,
+                    &mut alive_time,
+                    port_hub,
+                    config.compression(&session),
+                    &mut encryptor,
+                    stream,
+                )
+                .await?;
             }
 
             None => {
@@ -517,23 +884,51 @@ async fn process_tunnel_write<W: Write + Unpin, S: Stream<Item = TunnelMsg> + Un
                 break;
             }
         }
+
+        if let Some(len) = padding.as_mut().and_then(|padding| padding.next_padding()) {
+            let packed_buffer = pack_cs_frame(&Frame::Padding(len), &mut encryptor)?;
+            stream.write_all(&packed_buffer).await?;
+        }
+
+        if rekey && rekey_due(config, &encryptor, recv_bytes) {
+            info!("{}: rekey", port_hub.get_id());
+            let packed_buffer = pack_cs_frame(&Frame::Rekey, &mut encryptor)?;
+            stream.write_all(&packed_buffer).await?;
+            encryptor.rekey();
+            recv_bytes = 0;
+        }
     }
 
     Ok(())
 }
 
+// Counts the data of both directions, the server switches its key whenever
+// the client does.
+fn rekey_due(config: &TunnelConfig, encryptor: &Cryptor, recv_bytes: u64) -> bool {
+    let bytes = config
+        .rekey_bytes
+        .is_some_and(|bytes| encryptor.key_bytes() + recv_bytes >= bytes);
+    let interval = config
+        .rekey_interval
+        .is_some_and(|interval| encryptor.key_age() >= interval);
+
+    bytes || interval
+}
+
 async fn process_tunnel_msg<W: Write + Unpin>(
     msg: TunnelMsg,
     alive_time: &mut Instant,
     port_hub: &mut PortHub,
+    compression: Option<&CompressionMetrics>,
     encryptor: &mut Cryptor,
     stream: &mut W,
 ) -> std::io::Result<()> {
     match msg {
-        TunnelMsg::CSOpenPort(id, tx) => {
+        TunnelMsg::CSOpenPort(id, tx, credit_tx) => {
             info!("{}.{}: open port", port_hub.get_id(), id);
-            port_hub.add_port(id, tx);
-            stream.write_all(&pack_cs_open_port_msg(id)).await?;
+            port_hub.add_port(id, tx, credit_tx);
+            let packed_buffer = pack_cs_frame(&Frame::OpenPort(id), encryptor)?;
+            stream.write_all(&packed_buffer).await?;
         }
 
         TunnelMsg::CSConnect(id, buf) => {
@@ -542,8 +937,8 @@ async fn process_tunnel_msg<W: Write + Unpin>(
 
             port_hub.update_address(id, address);
 
-            let data = encryptor.encrypt(&buf);
-            stream.write_all(&pack_cs_connect_msg(id, &data)).await?;
+            let packed_buffer = pack_cs_frame(&Frame::Connect(id, buf), encryptor)?;
+            stream.write_all(&packed_buffer).await?;
         }
 
         TunnelMsg::CSConnectDN(id, buf, port) => {
@@ -553,8 +948,8 @@ async fn process_tunnel_msg<W: Write + Unpin>(
 
             port_hub.update_address(id, address);
 
-            let data = encryptor.encrypt(&buf);
-            let packed_buffer = pack_cs_connect_domain_msg(id, &data, port);
+            let frame = Frame::ConnectDomainName(id, buf, port);
+            let packed_buffer = pack_cs_frame(&frame, encryptor)?;
             stream.write_all(&packed_buffer).await?;
         }
 
@@ -564,26 +959,72 @@ async fn process_tunnel_msg<W: Write + Unpin>(
 
             port_hub.update_address(id, address);
 
-            let data = encryptor.encrypt(&buf);
-            stream.write_all(&pack_udp_associate_msg(id, &data)).await?;
+            let packed_buffer = pack_cs_frame(&Frame::UdpAssociate(id, buf), encryptor)?;
+            stream.write_all(&packed_buffer).await?;
+        }
+
+        TunnelMsg::CSResolve(id, buf) => {
+            let address = String::from_utf8(buf.clone()).unwrap_or_default();
+            info!("{}.{}: resolving {}", port_hub.get_id(), id, address);
+
+            port_hub.update_address(id, address);
+
+            let packed_buffer = pack_cs_frame(&Frame::Resolve(id, buf), encryptor)?;
+            stream.write_all(&packed_buffer).await?;
+        }
+
+        TunnelMsg::CSListen(id, buf) => {
+            let address = String::from_utf8(buf.clone()).unwrap_or_default();
+            info!("{}.{}: listen {}", port_hub.get_id(), id, address);
+
+            port_hub.update_address(id, address);
+
+            let packed_buffer = pack_cs_frame(&Frame::Listen(id, buf), encryptor)?;
+            stream.write_all(&packed_buffer).await?;
+        }
+
+        TunnelMsg::CSBind(id, buf) => {
+            let address = String::from_utf8(buf.clone()).unwrap_or_default();
+            info!("{}.{}: bind for {}", port_hub.get_id(), id, address);
+
+            port_hub.update_address(id, address);
+
+            let packed_buffer = pack_cs_frame(&Frame::Bind(id, buf), encryptor)?;
+            stream.write_all(&packed_buffer).await?;
         }
 
         TunnelMsg::CSShutdownWrite(id) => {
             info!("{}.{}: shutdown write", port_hub.get_id(), id);
             port_hub.client_shutdown(id);
-            stream.write_all(&pack_cs_shutdown_write_msg(id)).await?;
+            let packed_buffer = pack_cs_frame(&Frame::ShutdownWrite(id), encryptor)?;
+            stream.write_all(&packed_buffer).await?;
         }
 
         TunnelMsg::CSData(id, buf) => {
             debug!("{}.{} send {} bytes", port_hub.get_id(), id, buf.len());
-            let data = encryptor.encrypt(&buf);
-            stream.write_all(&pack_cs_data_msg(id, &data)).await?;
+            let frame = data_frame(id, buf, compression);
+            let packed_buffer = pack_cs_frame(&frame, encryptor)?;
+            stream.write_all(&packed_buffer).await?;
         }
 
         TunnelMsg::CSClosePort(id) => {
             info!("{}.{}: close port", port_hub.get_id(), id);
             port_hub.client_close_port(id);
-            stream.write_all(&pack_cs_close_port_msg(id)).await?;
+            let packed_buffer = pack_cs_frame(&Frame::ClosePort(id), encryptor)?;
+            stream.write_all(&packed_buffer).await?;
+        }
+
+        TunnelMsg::CSWindowUpdate(id, credit) => {
+            if port_hub.grant_window(id, credit) {
+                let frame = Frame::WindowUpdate(id, credit);
+                let packed_buffer = pack_cs_frame(&frame, encryptor)?;
+                stream.write_all(&packed_buffer).await?;
+            }
+        }
+
+        TunnelMsg::SCWindowUpdate(id, credit) => {
+            *alive_time = Instant::now();
+            port_hub.add_credit(id, credit);
         }
 
         TunnelMsg::SCHeartbeat => {
@@ -608,10 +1049,18 @@ async fn process_tunnel_msg<W: Write + Unpin>(
             port_hub.connect_ok(id, buf).await;
         }
 
+        TunnelMsg::SCConnectErr(id, err) => {
+            *alive_time = Instant::now();
+            port_hub.connect_err(id, err).await;
+        }
+
         TunnelMsg::SCData(id, buf) => {
             debug!("{}.{}: recv {} bytes", port_hub.get_id(), id, buf.len());
             *alive_time = Instant::now();
-            port_hub.server_send_data(id, buf).await;
+            if !port_hub.server_send_data(id, buf).await {
+                error!("{}.{}: server exceeded window", port_hub.get_id(), id);
+                return Err(std::io::Error::from(std::io::ErrorKind::InvalidData));
+            }
         }
 
         TunnelMsg::TunnelPortHalfDrop(id) => {
@@ -0,0 +1,89 @@
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::vec::Vec;
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+// Payloads shorter than this rarely shrink enough to pay for the CPU.
+const MIN_COMPRESS_SIZE: usize = 64;
+
+// Bytes of DATA payload before and after compression, both directions of
+// every tunnel that negotiated compression.
+pub struct CompressionMetrics {
+    raw_bytes: AtomicU64,
+    wire_bytes: AtomicU64,
+}
+
+impl CompressionMetrics {
+    pub fn new() -> Self {
+        Self {
+            raw_bytes: AtomicU64::new(0),
+            wire_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add(&self, raw_bytes: usize, wire_bytes: usize) {
+        self.raw_bytes
+            .fetch_add(raw_bytes as u64, Ordering::Relaxed);
+        self.wire_bytes
+            .fetch_add(wire_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn get_raw_bytes(&self) -> u64 {
+        self.raw_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn get_wire_bytes(&self) -> u64 {
+        self.wire_bytes.load(Ordering::Relaxed)
+    }
+
+    // Raw bytes per byte on the wire, 1 until any data went through.
+    pub fn get_ratio(&self) -> f64 {
+        let raw_bytes = self.get_raw_bytes();
+        let wire_bytes = self.get_wire_bytes();
+
+        if wire_bytes == 0 {
+            1.0
+        } else {
+            raw_bytes as f64 / wire_bytes as f64
+        }
+    }
+}
+
+impl Default for CompressionMetrics {
+    fn default() -> Self {
+        CompressionMetrics::new()
+    }
+}
+
+// Returns the deflated data, or None when it would not get smaller.
+pub fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < MIN_COMPRESS_SIZE {
+        return None;
+    }
+
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len()), Compression::fast());
+    encoder.write_all(data).ok()?;
+    let buf = encoder.finish().ok()?;
+
+    if buf.len() < data.len() {
+        Some(buf)
+    } else {
+        None
+    }
+}
+
+// Returns None when the data is not valid deflate or inflates beyond `limit`.
+pub fn decompress(data: &[u8], limit: usize) -> Option<Vec<u8>> {
+    let mut buf = Vec::new();
+    let mut decoder = DeflateDecoder::new(data).take(limit as u64 + 1);
+    decoder.read_to_end(&mut buf).ok()?;
+
+    if buf.len() > limit {
+        None
+    } else {
+        Some(buf)
+    }
+}
@@ -1,101 +1,232 @@
-use crypto::blockmodes::CtrMode;
-use crypto::blowfish::Blowfish;
-use crypto::buffer::{BufferResult, ReadBuffer, RefReadBuffer, RefWriteBuffer, WriteBuffer};
-use crypto::symmetriccipher::{Decryptor, Encryptor};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::curve25519::{curve25519, curve25519_base};
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::hmac::Hmac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::Sha256;
 use rand;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
-pub const CTR_SIZE: usize = 8;
+pub const TAG_SIZE: usize = 16;
+pub const KEY_SIZE: usize = 32;
+pub const PUBLIC_KEY_SIZE: usize = 32;
 
-pub struct Cryptor {
-    cryptor: CtrMode<Blowfish>,
-    ctr: Vec<u8>,
+const KDF_SALT: &[u8] = b"stunnel pre-shared key";
+const KDF_ITERATIONS: u32 = 200_000;
+
+const CS_KEY_INFO: &[u8] = b"stunnel cs key";
+const SC_KEY_INFO: &[u8] = b"stunnel sc key";
+const REKEY_INFO: &[u8] = b"stunnel rekey";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CipherSuite {
+    #[default]
+    ChaCha20Poly1305,
+    Aes256Gcm,
 }
 
-impl Cryptor {
-    pub fn new(key: &[u8]) -> Cryptor {
-        let mut ctr = vec![0u8, 0, 0, 0, 0, 0, 0, 0];
-        for x in ctr.iter_mut() {
-            *x = rand::random::<u8>()
+impl CipherSuite {
+    pub fn from_name(name: &str) -> Option<CipherSuite> {
+        match name {
+            "chacha20-poly1305" => Some(CipherSuite::ChaCha20Poly1305),
+            "aes-256-gcm" => Some(CipherSuite::Aes256Gcm),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => "chacha20-poly1305",
+            CipherSuite::Aes256Gcm => "aes-256-gcm",
+        }
+    }
+}
+
+// Per-tunnel session keys, one for each direction.
+pub struct SessionKeys {
+    pub cs: [u8; KEY_SIZE],
+    pub sc: [u8; KEY_SIZE],
+}
+
+// Ephemeral X25519 key pair, a new one for every tunnel. The session keys mix
+// the pre-shared key with the DH secret, so a leaked pre-shared key does not
+// decrypt tunnels recorded earlier.
+pub struct KeyExchange {
+    secret: [u8; 32],
+    public: [u8; PUBLIC_KEY_SIZE],
+}
+
+impl KeyExchange {
+    pub fn new() -> KeyExchange {
+        let mut secret = [0u8; 32];
+        for x in secret.iter_mut() {
+            *x = rand::random::<u8>();
         }
 
-        Cryptor::with_ctr(key, ctr)
+        let public = curve25519_base(&secret);
+        KeyExchange { secret, public }
     }
 
-    pub fn with_ctr(key: &[u8], ctr: Vec<u8>) -> Cryptor {
-        let algo = Blowfish::new(key);
-        let cryptor = CtrMode::new(algo, ctr.clone());
+    pub fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+
+    // Returns None when the peer sent a low order point.
+    pub fn session_keys(
+        &self,
+        key: &[u8],
+        peer_public: &[u8],
+        client_public: &[u8],
+        server_public: &[u8],
+    ) -> Option<SessionKeys> {
+        let shared = curve25519(&self.secret, peer_public);
+        if shared.iter().all(|&x| x == 0) {
+            return None;
+        }
+
+        let mut salt = Vec::with_capacity(client_public.len() + server_public.len());
+        salt.extend_from_slice(client_public);
+        salt.extend_from_slice(server_public);
+
+        let mut ikm = Vec::with_capacity(key.len() + shared.len());
+        ikm.extend_from_slice(key);
+        ikm.extend_from_slice(&shared);
+
+        let mut prk = [0u8; 32];
+        let mut keys = SessionKeys {
+            cs: [0u8; KEY_SIZE],
+            sc: [0u8; KEY_SIZE],
+        };
+        hkdf_extract(Sha256::new(), &salt, &ikm, &mut prk);
+        hkdf_expand(Sha256::new(), &prk, CS_KEY_INFO, &mut keys.cs);
+        hkdf_expand(Sha256::new(), &prk, SC_KEY_INFO, &mut keys.sc);
+
+        Some(keys)
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        KeyExchange::new()
+    }
+}
+
+// Seals the frames of one direction with a session key. The nonce is a frame
+// counter, so frames cannot be replayed, reordered or dropped without the
+// next tag check failing.
+pub struct Cryptor {
+    suite: CipherSuite,
+    key: [u8; KEY_SIZE],
+    nonce: u64,
+    key_time: Instant,
+    key_bytes: u64,
+}
+
+impl Cryptor {
+    pub fn new(suite: CipherSuite, key: [u8; KEY_SIZE]) -> Cryptor {
         Cryptor {
-            cryptor: cryptor,
-            ctr: ctr,
+            suite,
+            key,
+            nonce: 0,
+            key_time: Instant::now(),
+            key_bytes: 0,
         }
     }
 
-    pub fn key_size_range() -> (usize, usize) {
-        (4, 56)
+    // Stretches a passphrase of any length into the pre-shared key. Both sides
+    // do this once at startup, a brute-force attacker pays it per guess.
+    pub fn derive_key(passphrase: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::new(Sha256::new(), passphrase);
+        let mut key = vec![0u8; KEY_SIZE];
+        pbkdf2(&mut mac, KDF_SALT, KDF_ITERATIONS, &mut key);
+        key
+    }
+
+    pub fn suite(&self) -> CipherSuite {
+        self.suite
     }
 
-    pub fn ctr_size() -> usize {
-        CTR_SIZE
+    // Bytes sealed or opened with the current key.
+    pub fn key_bytes(&self) -> u64 {
+        self.key_bytes
     }
 
-    pub fn ctr_as_slice(&self) -> &[u8] {
-        &self.ctr
+    pub fn key_age(&self) -> Duration {
+        self.key_time.elapsed()
     }
 
-    pub fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
-        let mut result = Vec::<u8>::new();
-        let mut read_buffer = RefReadBuffer::new(data);
-        let mut buffer = [0; 2048];
-        let mut write_buffer = RefWriteBuffer::new(&mut buffer);
-
-        loop {
-            let res = self
-                .cryptor
-                .encrypt(&mut read_buffer, &mut write_buffer, false)
-                .unwrap();
-            result.extend(
-                write_buffer
-                    .take_read_buffer()
-                    .take_remaining()
-                    .iter()
-                    .map(|&i| i),
-            );
-
-            match res {
-                BufferResult::BufferUnderflow => break,
-                BufferResult::BufferOverflow => {}
+    // Replaces the key with the next one of a one-way chain, both sides
+    // switch at the same frame so no key material goes over the wire.
+    pub fn rekey(&mut self) {
+        let mut key = [0u8; KEY_SIZE];
+        hkdf_expand(Sha256::new(), &self.key, REKEY_INFO, &mut key);
+
+        self.key = key;
+        self.nonce = 0;
+        self.key_time = Instant::now();
+        self.key_bytes = 0;
+    }
+
+    // Returns the ciphertext with the auth tag appended.
+    pub fn encrypt(&mut self, aad: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut result = vec![0u8; data.len() + TAG_SIZE];
+        let (output, tag) = result.split_at_mut(data.len());
+        let nonce = self.next_nonce();
+        self.key_bytes += data.len() as u64;
+
+        match self.suite {
+            CipherSuite::ChaCha20Poly1305 => {
+                let mut cipher = ChaCha20Poly1305::new(&self.key, &nonce[..8], aad);
+                cipher.encrypt(data, output, tag);
+            }
+
+            CipherSuite::Aes256Gcm => {
+                let mut cipher = AesGcm::new(KeySize::KeySize256, &self.key, &nonce, aad);
+                cipher.encrypt(data, output, tag);
             }
         }
 
         result
     }
 
-    pub fn decrypt(&mut self, data: &[u8]) -> Vec<u8> {
-        let mut result = Vec::<u8>::new();
-        let mut read_buffer = RefReadBuffer::new(data);
-        let mut buffer = [0; 2048];
-        let mut write_buffer = RefWriteBuffer::new(&mut buffer);
-
-        loop {
-            let res = self
-                .cryptor
-                .decrypt(&mut read_buffer, &mut write_buffer, false)
-                .unwrap();
-            result.extend(
-                write_buffer
-                    .take_read_buffer()
-                    .take_remaining()
-                    .iter()
-                    .map(|&i| i),
-            );
-
-            match res {
-                BufferResult::BufferUnderflow => break,
-                BufferResult::BufferOverflow => {}
+    // Returns None when the data is truncated or the auth tag mismatches.
+    pub fn decrypt(&mut self, aad: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < TAG_SIZE {
+            return None;
+        }
+
+        let (input, tag) = data.split_at(data.len() - TAG_SIZE);
+        let mut result = vec![0u8; input.len()];
+        let nonce = self.next_nonce();
+        self.key_bytes += input.len() as u64;
+
+        let ok = match self.suite {
+            CipherSuite::ChaCha20Poly1305 => {
+                let mut cipher = ChaCha20Poly1305::new(&self.key, &nonce[..8], aad);
+                cipher.decrypt(input, &mut result, tag)
             }
+
+            CipherSuite::Aes256Gcm => {
+                let mut cipher = AesGcm::new(KeySize::KeySize256, &self.key, &nonce, aad);
+                cipher.decrypt(input, &mut result, tag)
+            }
+        };
+
+        if ok {
+            Some(result)
+        } else {
+            None
         }
+    }
 
-        result
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..8].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce += 1;
+        nonce
     }
 }
@@ -0,0 +1,621 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+use async_std::io;
+use async_std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use async_std::prelude::*;
+
+const TYPE_A: u16 = 1;
+const TYPE_SOA: u16 = 6;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+const OPCODE_MASK: u16 = 0x7800;
+const RCODE_MASK: u16 = 0x000F;
+const RCODE_NOERROR: u16 = 0;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+
+const HEADER_SIZE: usize = 12;
+const MAX_UDP_SIZE: usize = 4096;
+// Responses of the forwarder fit a UDP message without EDNS.
+const MAX_RESPONSE_SIZE: usize = 512;
+const MAX_NAME_SIZE: usize = 253;
+const MAX_LABEL_SIZE: usize = 63;
+
+// Answers stay cached within these bounds, whatever their TTL says.
+const MIN_TTL: u32 = 1;
+const MAX_TTL: u32 = 86400;
+// For negative answers without an SOA record.
+const DEFAULT_NEGATIVE_TTL: u32 = 30;
+// The system resolver tells no TTL.
+const SYSTEM_TTL: u32 = 60;
+const MAX_CACHE_ENTRIES: usize = 10000;
+// The forwarder learns no TTL from the server.
+const ANSWER_TTL: u32 = 60;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Upstream {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+impl Upstream {
+    // Parses `udp://ip[:port]`, `tcp://ip[:port]` or a bare `ip[:port]` for
+    // UDP, the port defaults to 53.
+    pub fn parse(s: &str) -> Option<Upstream> {
+        let (tcp, addr) = match s.strip_prefix("tcp://") {
+            Some(addr) => (true, addr),
+            None => (false, s.strip_prefix("udp://").unwrap_or(s)),
+        };
+
+        let addr = addr.parse::<SocketAddr>().ok().or_else(|| {
+            addr.trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .ok()
+                .map(|ip| SocketAddr::new(ip, 53))
+        })?;
+
+        if tcp {
+            Some(Upstream::Tcp(addr))
+        } else {
+            Some(Upstream::Udp(addr))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResolveError {
+    NotFound,
+    Timeout,
+    Failed,
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::NotFound => write!(f, "name not found"),
+            ResolveError::Timeout => write!(f, "resolution timed out"),
+            ResolveError::Failed => write!(f, "resolution failed"),
+        }
+    }
+}
+
+pub struct ResolverMetrics {
+    queries: AtomicU64,
+    hits: AtomicU64,
+    lookups: AtomicU64,
+    failures: AtomicU64,
+    latency_us: AtomicU64,
+}
+
+impl ResolverMetrics {
+    fn new() -> Self {
+        Self {
+            queries: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            lookups: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            latency_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get_queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    pub fn get_hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    // Queries that went to the upstreams.
+    pub fn get_lookups(&self) -> u64 {
+        self.lookups.load(Ordering::Relaxed)
+    }
+
+    // Lookups that timed out or got no usable answer.
+    pub fn get_failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    pub fn get_hit_rate(&self) -> f64 {
+        match self.get_queries() {
+            0 => 0.0,
+            queries => self.get_hits() as f64 / queries as f64,
+        }
+    }
+
+    pub fn get_average_latency_ms(&self) -> f64 {
+        match self.get_lookups() {
+            0 => 0.0,
+            lookups => self.latency_us.load(Ordering::Relaxed) as f64 / lookups as f64 / 1000.0,
+        }
+    }
+}
+
+struct CacheEntry {
+    result: Result<Vec<IpAddr>, ResolveError>,
+    expires: Instant,
+}
+
+// Resolves names to addresses with the configured upstreams, or the system
+// resolver when there are none. Answers and NXDOMAINs are cached for their
+// TTL.
+pub struct Resolver {
+    upstreams: Vec<Upstream>,
+    timeout: Duration,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+    metrics: ResolverMetrics,
+}
+
+impl Resolver {
+    pub fn new(upstreams: Vec<Upstream>, timeout: Duration) -> Resolver {
+        Resolver {
+            upstreams,
+            timeout,
+            cache: Mutex::new(HashMap::new()),
+            metrics: ResolverMetrics::new(),
+        }
+    }
+
+    pub fn metrics(&self) -> &ResolverMetrics {
+        &self.metrics
+    }
+
+    pub fn cache_size(&self) -> usize {
+        self.cache.lock().unwrap().len()
+    }
+
+    pub async fn resolve(&self, name: &str) -> Result<Vec<IpAddr>, ResolveError> {
+        if let Ok(ip) = name.trim_start_matches('[').trim_end_matches(']').parse() {
+            return Ok(vec![ip]);
+        }
+
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.metrics.queries.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(result) = self.cached(&name) {
+            self.metrics.hits.fetch_add(1, Ordering::Relaxed);
+            return result;
+        }
+
+        let start = Instant::now();
+        let (result, ttl) = self.lookup(&name).await;
+        let latency = start.elapsed().as_micros() as u64;
+
+        self.metrics.lookups.fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .latency_us
+            .fetch_add(latency, Ordering::Relaxed);
+
+        match result {
+            Err(ResolveError::Timeout) | Err(ResolveError::Failed) => {
+                self.metrics.failures.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => self.insert(name, result.clone(), ttl),
+        }
+
+        result
+    }
+
+    fn cached(&self, name: &str) -> Option<Result<Vec<IpAddr>, ResolveError>> {
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(name)
+            .filter(|entry| entry.expires > Instant::now())
+            .map(|entry| entry.result.clone())
+    }
+
+    fn insert(&self, name: String, result: Result<Vec<IpAddr>, ResolveError>, ttl: u32) {
+        let ttl = ttl.clamp(MIN_TTL, MAX_TTL);
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.retain(|_, entry| entry.expires > now);
+            if cache.len() >= MAX_CACHE_ENTRIES {
+                cache.clear();
+            }
+        }
+
+        let expires = now + Duration::from_secs(ttl as u64);
+        cache.insert(name, CacheEntry { result, expires });
+    }
+
+    // Returns the result along with how long it may be cached.
+    async fn lookup(&self, name: &str) -> (Result<Vec<IpAddr>, ResolveError>, u32) {
+        if self.upstreams.is_empty() {
+            let addrs = io::timeout(self.timeout, (name, 0).to_socket_addrs()).await;
+            return match addrs {
+                Ok(addrs) => (Ok(addrs.map(|addr| addr.ip()).collect()), SYSTEM_TTL),
+                Err(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                    (Err(ResolveError::Timeout), 0)
+                }
+                Err(err) if is_no_such_host(&err) => {
+                    (Err(ResolveError::NotFound), DEFAULT_NEGATIVE_TTL)
+                }
+                Err(_) => (Err(ResolveError::Failed), 0),
+            };
+        }
+
+        // The upstreams share the timeout, so a dead one leaves time for the
+        // next.
+        let timeout = self.timeout / self.upstreams.len() as u32;
+        let mut error = ResolveError::Failed;
+
+        for upstream in self.upstreams.iter() {
+            let a = query(upstream, name, TYPE_A);
+            let aaaa = query(upstream, name, TYPE_AAAA);
+            let answers = io::timeout(timeout, async { Ok(a.join(aaaa).await) }).await;
+
+            let (a, aaaa) = match answers {
+                Ok((Ok(a), Ok(aaaa))) => (a, aaaa),
+                Ok(_) => continue,
+                Err(_) => {
+                    error = ResolveError::Timeout;
+                    continue;
+                }
+            };
+
+            if !a.addrs.is_empty() || !aaaa.addrs.is_empty() {
+                let ttl = a.ttl.min(aaaa.ttl);
+                let mut addrs = a.addrs;
+                addrs.extend(aaaa.addrs);
+                return (Ok(addrs), ttl);
+            }
+
+            let found = |rcode| rcode == RCODE_NOERROR || rcode == RCODE_NXDOMAIN;
+            if found(a.rcode) && found(aaaa.rcode) {
+                let ttl = a.negative_ttl.min(aaaa.negative_ttl);
+                return (Err(ResolveError::NotFound), ttl);
+            }
+        }
+
+        (Err(error), 0)
+    }
+}
+
+// The system resolver reports getaddrinfo errors only as text, under the
+// context async-std adds. EAI_NONAME and EAI_NODATA mean the name has no
+// address, the rest, such as EAI_AGAIN, may pass.
+fn is_no_such_host(err: &std::io::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(err);
+
+    while let Some(err) = source {
+        let message = err.to_string().to_ascii_lowercase();
+        if message.contains("not known") || message.contains("no address associated") {
+            return true;
+        }
+        source = err.source();
+    }
+
+    false
+}
+
+// A query received by the DNS forwarder of the client. Only A and AAAA
+// questions go to the server, anything else is answered with NOTIMP.
+pub struct Query {
+    id: u16,
+    flags: u16,
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    // The question section as received, echoed in the response.
+    question: Vec<u8>,
+}
+
+impl Query {
+    // Returns None when the message is no standard query of one name.
+    pub fn parse(buf: &[u8]) -> Option<Query> {
+        let flags = read_u16(buf, 2)?;
+        if flags & (FLAG_QR | OPCODE_MASK) != 0 || read_u16(buf, 4)? != 1 {
+            return None;
+        }
+
+        let mut labels = Vec::new();
+        let mut pos = HEADER_SIZE;
+        loop {
+            let len = *buf.get(pos)? as usize;
+            pos += 1;
+
+            if len == 0 {
+                break;
+            }
+
+            let label = buf.get(pos..pos + len).filter(|_| len <= MAX_LABEL_SIZE)?;
+            if !label.iter().all(|&c| c.is_ascii_graphic() && c != b'.') {
+                return None;
+            }
+
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += len;
+        }
+
+        let name = labels.join(".");
+        if name.len() > MAX_NAME_SIZE {
+            return None;
+        }
+
+        Some(Query {
+            id: read_u16(buf, 0)?,
+            flags,
+            name,
+            qtype: read_u16(buf, pos)?,
+            qclass: read_u16(buf, pos + 2)?,
+            question: buf[HEADER_SIZE..pos + 4].to_vec(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn wants_address(&self) -> bool {
+        self.qclass == CLASS_IN
+            && (self.qtype == TYPE_A || self.qtype == TYPE_AAAA)
+            && !self.name.is_empty()
+    }
+
+    // Answers with the addresses of the queried family.
+    pub fn answer(&self, result: Result<Vec<IpAddr>, ResolveError>) -> Vec<u8> {
+        let addrs = match result {
+            Ok(addrs) => addrs,
+            Err(ResolveError::NotFound) => return self.response(RCODE_NXDOMAIN, &[]),
+            Err(_) => return self.response(RCODE_SERVFAIL, &[]),
+        };
+
+        let addrs: Vec<IpAddr> = addrs
+            .into_iter()
+            .filter(|addr| addr.is_ipv4() == (self.qtype == TYPE_A))
+            .collect();
+
+        self.response(RCODE_NOERROR, &addrs)
+    }
+
+    pub fn not_implemented(&self) -> Vec<u8> {
+        self.response(RCODE_NOTIMP, &[])
+    }
+
+    fn response(&self, rcode: u16, addrs: &[IpAddr]) -> Vec<u8> {
+        let mut records = Vec::new();
+        let mut size = HEADER_SIZE + self.question.len();
+
+        for addr in addrs {
+            let mut record = Vec::new();
+            // The name is a pointer to the one of the question.
+            record.extend_from_slice(&[0xC0, HEADER_SIZE as u8]);
+            record.extend_from_slice(&self.qtype.to_be_bytes());
+            record.extend_from_slice(&CLASS_IN.to_be_bytes());
+            record.extend_from_slice(&ANSWER_TTL.to_be_bytes());
+
+            match addr {
+                IpAddr::V4(ip) => {
+                    record.extend_from_slice(&4u16.to_be_bytes());
+                    record.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    record.extend_from_slice(&16u16.to_be_bytes());
+                    record.extend_from_slice(&ip.octets());
+                }
+            }
+
+            // Addresses that do not fit are left out, a stub has plenty with
+            // the rest.
+            size += record.len();
+            if size > MAX_RESPONSE_SIZE {
+                break;
+            }
+            records.push(record);
+        }
+
+        let flags = FLAG_QR | (self.flags & FLAG_RD) | FLAG_RA | rcode;
+
+        let mut buf = Vec::with_capacity(size);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&1u16.to_be_bytes());
+        buf.extend_from_slice(&(records.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        buf.extend_from_slice(&self.question);
+
+        for record in records {
+            buf.extend_from_slice(&record);
+        }
+
+        buf
+    }
+}
+
+struct Answer {
+    rcode: u16,
+    addrs: Vec<IpAddr>,
+    // Smallest TTL of the address records.
+    ttl: u32,
+    // How long the absence of records may be cached.
+    negative_ttl: u32,
+}
+
+async fn query(upstream: &Upstream, name: &str, qtype: u16) -> std::io::Result<Answer> {
+    let invalid = || std::io::Error::from(std::io::ErrorKind::InvalidData);
+    let id = rand::random::<u16>();
+    let request = build_query(id, name, qtype)
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+
+    let response = match *upstream {
+        Upstream::Udp(addr) => {
+            let response = query_udp(addr, &request).await?;
+            if is_truncated(&response) {
+                query_tcp(addr, &request).await?
+            } else {
+                response
+            }
+        }
+
+        Upstream::Tcp(addr) => query_tcp(addr, &request).await?,
+    };
+
+    parse_answer(id, &response).ok_or_else(invalid)
+}
+
+async fn query_udp(addr: SocketAddr, request: &[u8]) -> std::io::Result<Vec<u8>> {
+    let local_addr = match addr {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+
+    let socket = UdpSocket::bind(local_addr).await?;
+    socket.connect(addr).await?;
+    socket.send(request).await?;
+
+    let mut buf = vec![0u8; MAX_UDP_SIZE];
+    loop {
+        let n = socket.recv(&mut buf).await?;
+        if n >= 2 && buf[..2] == request[..2] {
+            buf.truncate(n);
+            return Ok(buf);
+        }
+    }
+}
+
+// Messages over TCP go with a 2 byte length in front.
+async fn query_tcp(addr: SocketAddr, request: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr).await?;
+
+    let mut buf = Vec::with_capacity(2 + request.len());
+    buf.extend_from_slice(&(request.len() as u16).to_be_bytes());
+    buf.extend_from_slice(request);
+    stream.write_all(&buf).await?;
+
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+
+    let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+fn build_query(id: u16, name: &str, qtype: u16) -> Option<Vec<u8>> {
+    if name.is_empty() || name.len() > MAX_NAME_SIZE {
+        return None;
+    }
+
+    let mut buf = Vec::with_capacity(HEADER_SIZE + name.len() + 6);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&FLAG_RD.to_be_bytes());
+    buf.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_SIZE {
+            return None;
+        }
+
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+
+    buf.push(0);
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Some(buf)
+}
+
+fn is_truncated(buf: &[u8]) -> bool {
+    read_u16(buf, 2).is_some_and(|flags| flags & FLAG_TC != 0)
+}
+
+// Returns None when the response does not answer query `id` or is malformed.
+fn parse_answer(id: u16, buf: &[u8]) -> Option<Answer> {
+    let flags = read_u16(buf, 2)?;
+    if read_u16(buf, 0)? != id || flags & FLAG_QR == 0 {
+        return None;
+    }
+
+    let qdcount = read_u16(buf, 4)?;
+    let ancount = read_u16(buf, 6)? as usize;
+    let nscount = read_u16(buf, 8)? as usize;
+
+    let mut pos = HEADER_SIZE;
+    for _ in 0..qdcount {
+        pos = skip_name(buf, pos)? + 4;
+    }
+
+    let mut answer = Answer {
+        rcode: flags & RCODE_MASK,
+        addrs: Vec::new(),
+        ttl: MAX_TTL,
+        negative_ttl: DEFAULT_NEGATIVE_TTL,
+    };
+
+    for i in 0..ancount + nscount {
+        pos = skip_name(buf, pos)?;
+        let rtype = read_u16(buf, pos)?;
+        let ttl = read_u32(buf, pos + 4)?;
+        let len = read_u16(buf, pos + 8)? as usize;
+        let rdata = buf.get(pos + 10..pos + 10 + len)?;
+        pos += 10 + len;
+
+        match (rtype, rdata.len(), i < ancount) {
+            (TYPE_A, 4, true) => {
+                let octets = [rdata[0], rdata[1], rdata[2], rdata[3]];
+                answer.addrs.push(IpAddr::V4(Ipv4Addr::from(octets)));
+                answer.ttl = answer.ttl.min(ttl);
+            }
+
+            (TYPE_AAAA, 16, true) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                answer.addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+                answer.ttl = answer.ttl.min(ttl);
+            }
+
+            // RFC 2308: a negative answer lives as long as the SOA record
+            // and its minimum field allow.
+            (TYPE_SOA, len, false) if len >= 4 => {
+                let minimum = read_u32(rdata, len - 4)?;
+                answer.negative_ttl = ttl.min(minimum);
+            }
+
+            _ => {}
+        }
+    }
+
+    Some(answer)
+}
+
+// Returns the position after the name, which may end in a compression
+// pointer.
+fn skip_name(buf: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *buf.get(pos)? as usize;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+
+        if len & 0xC0 == 0xC0 {
+            return Some(pos + 2);
+        }
+
+        pos += 1 + len;
+    }
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Option<u16> {
+    let bytes = buf.get(pos..pos + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Option<u32> {
+    let bytes = buf.get(pos..pos + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
@@ -0,0 +1,708 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::vec::Vec;
+
+use async_std::io::{Read, Write};
+use async_std::prelude::*;
+
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+
+use super::cryptor::*;
+
+const MAC_SIZE: usize = 32;
+const KEY_ID_SIZE: usize = 8;
+const NONCE_SIZE: usize = 32;
+const TIMESTAMP_SIZE: usize = 8;
+const VERSION_SIZE: usize = 1;
+const CAPABILITIES_SIZE: usize = 4;
+const MAX_TIME_SKEW_SECS: u64 = 90;
+
+const CLIENT_HELLO_SIZE: usize =
+    PUBLIC_KEY_SIZE + KEY_ID_SIZE + TIMESTAMP_SIZE + VERSION_SIZE + CAPABILITIES_SIZE + MAC_SIZE;
+const SERVER_HELLO_SIZE: usize =
+    PUBLIC_KEY_SIZE + NONCE_SIZE + VERSION_SIZE + CAPABILITIES_SIZE + MAC_SIZE;
+
+// The version of the frame layout. A peer speaks the lower of both versions,
+// and refuses versions below the minimum.
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+pub mod capability {
+    pub const CHACHA20_POLY1305: u32 = 1 << 0;
+    pub const AES_256_GCM: u32 = 1 << 1;
+    pub const REKEY: u32 = 1 << 2;
+    pub const PADDING: u32 = 1 << 3;
+    pub const FLOW_CONTROL: u32 = 1 << 4;
+    // Opt-in, the client offers it and the server may turn it down.
+    pub const COMPRESSION: u32 = 1 << 5;
+    pub const CONNECT_ERROR: u32 = 1 << 6;
+    pub const RESOLVE: u32 = 1 << 7;
+    pub const REMOTE_FORWARD: u32 = 1 << 8;
+    pub const BIND: u32 = 1 << 9;
+
+    pub const CIPHER_SUITES: u32 = CHACHA20_POLY1305 | AES_256_GCM;
+    pub const SUPPORTED: u32 =
+        REKEY | PADDING | FLOW_CONTROL | CONNECT_ERROR | RESOLVE | REMOTE_FORWARD | BIND;
+}
+
+// What both sides of a tunnel agreed on in the handshake.
+#[derive(Clone, Copy, Debug)]
+pub struct Session {
+    pub version: u8,
+    pub capabilities: u32,
+}
+
+impl Session {
+    pub fn supports(&self, capability: u32) -> bool {
+        self.capabilities & capability == capability
+    }
+}
+
+const KEY_ID_LABEL: &[u8] = b"stunnel key id";
+const CLIENT_HELLO_LABEL: &[u8] = b"stunnel client hello";
+const SERVER_HELLO_LABEL: &[u8] = b"stunnel server hello";
+const CLIENT_FINISH_LABEL: &[u8] = b"stunnel client finish";
+
+struct User {
+    id: String,
+    key: Vec<u8>,
+}
+
+// Keys of all users allowed to open tunnels on a server. A client hello names
+// its key by a MAC over the ephemeral public key, so the identifier differs in
+// every handshake and cannot be used to track a user.
+pub struct UserTable {
+    users: Vec<User>,
+}
+
+impl UserTable {
+    pub fn new() -> Self {
+        Self { users: Vec::new() }
+    }
+
+    pub fn add_user(&mut self, id: String, key: Vec<u8>) {
+        self.users.push(User { id, key });
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    fn find_user(&self, client_public: &[u8], key_id: &[u8]) -> Option<&User> {
+        self.users.iter().find(|user| {
+            let mac = compute_mac(&user.key, KEY_ID_LABEL, &[client_public]);
+            fixed_time_eq(&mac[..KEY_ID_SIZE], key_id)
+        })
+    }
+}
+
+impl Default for UserTable {
+    fn default() -> Self {
+        UserTable::new()
+    }
+}
+
+// Reads a users file of one `id passphrase` per line, the passphrase running
+// to the end of the line, as the server and the client proxies take it. Fails
+// on a line without a passphrase or with an id seen before, naming the line.
+pub fn load_users(path: &str) -> std::io::Result<Vec<(String, String)>> {
+    let mut users: Vec<(String, String)> = Vec::new();
+
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.splitn(2, char::is_whitespace);
+        let id = fields.next().unwrap_or_default();
+        let passphrase = fields.next().unwrap_or_default().trim();
+
+        let invalid = |reason: &str| {
+            let message = format!("line {}: {}", number + 1, reason);
+            Error::new(ErrorKind::InvalidData, message)
+        };
+
+        if passphrase.is_empty() {
+            return Err(invalid("empty passphrase"));
+        }
+
+        if users.iter().any(|(other, _)| other == id) {
+            return Err(invalid("duplicate user id"));
+        }
+
+        users.push((id.to_string(), passphrase.to_string()));
+    }
+
+    Ok(users)
+}
+
+// Remembers the ephemeral public keys of accepted client hellos for as long
+// as their timestamps are acceptable, so a captured hello cannot be replayed.
+pub struct ReplayCache {
+    seen: Mutex<HashMap<Vec<u8>, Instant>>,
+}
+
+impl ReplayCache {
+    pub fn new() -> Self {
+        Self {
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn check_and_insert(&self, id: &[u8]) -> bool {
+        let now = Instant::now();
+        let ttl = Duration::from_secs(MAX_TIME_SKEW_SECS * 2);
+        let mut seen = self.seen.lock().unwrap();
+
+        seen.retain(|_, time| now.duration_since(*time) < ttl);
+        if seen.contains_key(id) {
+            return false;
+        }
+
+        seen.insert(id.to_vec(), now);
+        true
+    }
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        ReplayCache::new()
+    }
+}
+
+// client -> server: client public key, key id, timestamp, version,
+//                   capabilities, mac
+// server -> client: server public key, nonce, version, capabilities,
+//                   mac over client hello
+// client -> server: mac over both hellos, proving the key over the nonce
+//
+// The client offers its cipher suite along with `capabilities`, the server
+// answers with the common ones and exactly one cipher suite.
+pub async fn client_handshake<R: Read + Unpin, W: Write + Unpin>(
+    key: &[u8],
+    suite: CipherSuite,
+    capabilities: u32,
+    reader: &mut R,
+    writer: &mut W,
+) -> std::io::Result<(Session, Cryptor, Cryptor)> {
+    let offered = capabilities | suite_capability(suite);
+    let exchange = KeyExchange::new();
+
+    let key_id = compute_mac(key, KEY_ID_LABEL, &[exchange.public_key()]);
+
+    let mut client_hello = Vec::with_capacity(CLIENT_HELLO_SIZE);
+    client_hello.extend_from_slice(exchange.public_key());
+    client_hello.extend_from_slice(&key_id[..KEY_ID_SIZE]);
+    client_hello.extend_from_slice(&unix_timestamp().to_be_bytes());
+    client_hello.push(PROTOCOL_VERSION);
+    client_hello.extend_from_slice(&offered.to_be_bytes());
+    let mac = compute_mac(key, CLIENT_HELLO_LABEL, &[&client_hello]);
+    client_hello.extend_from_slice(&mac);
+    writer.write_all(&client_hello).await?;
+
+    let mut server_hello = [0u8; SERVER_HELLO_SIZE];
+    reader.read_exact(&mut server_hello).await?;
+
+    let (server_body, mac) = server_hello.split_at(SERVER_HELLO_SIZE - MAC_SIZE);
+    if !verify_mac(key, SERVER_HELLO_LABEL, &[&client_hello, server_body], mac) {
+        return Err(Error::new(ErrorKind::InvalidData, "bad server hello"));
+    }
+
+    let (server_public, rest) = server_body.split_at(PUBLIC_KEY_SIZE);
+    let session = parse_session(&rest[NONCE_SIZE..]);
+
+    if session.version < MIN_PROTOCOL_VERSION || session.version > PROTOCOL_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "unsupported protocol version",
+        ));
+    }
+
+    if session.capabilities & !offered != 0 || !session.supports(suite_capability(suite)) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "bad server capabilities",
+        ));
+    }
+
+    let keys = exchange
+        .session_keys(key, server_public, exchange.public_key(), server_public)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "bad server public key"))?;
+
+    let finish = compute_mac(key, CLIENT_FINISH_LABEL, &[&client_hello, &server_hello]);
+    writer.write_all(&finish).await?;
+
+    Ok((
+        session,
+        Cryptor::new(suite, keys.cs),
+        Cryptor::new(suite, keys.sc),
+    ))
+}
+
+// Returns the id of the authenticated user along with the session and the
+// cryptors. `suite` is preferred when the client offers it, otherwise the
+// cipher suite of the client is accepted. Only offered `capabilities` are
+// agreed on.
+pub async fn server_handshake<R: Read + Unpin, W: Write + Unpin>(
+    users: &UserTable,
+    suite: CipherSuite,
+    capabilities: u32,
+    replay_cache: &ReplayCache,
+    reader: &mut R,
+    writer: &mut W,
+) -> std::io::Result<(String, Session, Cryptor, Cryptor)> {
+    let mut client_hello = [0u8; CLIENT_HELLO_SIZE];
+    reader.read_exact(&mut client_hello).await?;
+
+    let (client_body, mac) = client_hello.split_at(CLIENT_HELLO_SIZE - MAC_SIZE);
+    let (client_public, rest) = client_body.split_at(PUBLIC_KEY_SIZE);
+    let (key_id, rest) = rest.split_at(KEY_ID_SIZE);
+    let (timestamp, offer) = rest.split_at(TIMESTAMP_SIZE);
+
+    let user = users
+        .find_user(client_public, key_id)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unknown client key"))?;
+    let key = &user.key[..];
+
+    if !verify_mac(key, CLIENT_HELLO_LABEL, &[client_body], mac) {
+        return Err(Error::new(ErrorKind::InvalidData, "bad client hello"));
+    }
+
+    let mut buf = [0u8; TIMESTAMP_SIZE];
+    buf.copy_from_slice(timestamp);
+    let timestamp = u64::from_be_bytes(buf);
+    let now = unix_timestamp();
+
+    if now.abs_diff(timestamp) > MAX_TIME_SKEW_SECS {
+        return Err(Error::new(ErrorKind::InvalidData, "client hello expired"));
+    }
+
+    if !replay_cache.check_and_insert(client_public) {
+        return Err(Error::new(ErrorKind::InvalidData, "client hello replayed"));
+    }
+
+    let offer = parse_session(offer);
+    let version = offer.version.min(PROTOCOL_VERSION);
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "unsupported protocol version",
+        ));
+    }
+
+    let suite = if offer.supports(suite_capability(suite)) {
+        suite
+    } else {
+        SUITES
+            .iter()
+            .copied()
+            .find(|&suite| offer.supports(suite_capability(suite)))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "no common cipher suite"))?
+    };
+
+    let session = Session {
+        version,
+        capabilities: (offer.capabilities & capabilities) | suite_capability(suite),
+    };
+
+    let exchange = KeyExchange::new();
+    let mut server_hello = Vec::with_capacity(SERVER_HELLO_SIZE);
+    server_hello.extend_from_slice(exchange.public_key());
+    server_hello.extend((0..NONCE_SIZE).map(|_| rand::random::<u8>()));
+    server_hello.push(session.version);
+    server_hello.extend_from_slice(&session.capabilities.to_be_bytes());
+    let mac = compute_mac(key, SERVER_HELLO_LABEL, &[&client_hello, &server_hello]);
+    server_hello.extend_from_slice(&mac);
+    writer.write_all(&server_hello).await?;
+
+    let mut finish = [0u8; MAC_SIZE];
+    reader.read_exact(&mut finish).await?;
+
+    if !verify_mac(
+        key,
+        CLIENT_FINISH_LABEL,
+        &[&client_hello, &server_hello],
+        &finish,
+    ) {
+        return Err(Error::new(ErrorKind::InvalidData, "bad client finish"));
+    }
+
+    let keys = exchange
+        .session_keys(key, client_public, client_public, exchange.public_key())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "bad client public key"))?;
+
+    Ok((
+        user.id.clone(),
+        session,
+        Cryptor::new(suite, keys.sc),
+        Cryptor::new(suite, keys.cs),
+    ))
+}
+
+const SUITES: [CipherSuite; 2] = [CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm];
+
+fn suite_capability(suite: CipherSuite) -> u32 {
+    match suite {
+        CipherSuite::ChaCha20Poly1305 => capability::CHACHA20_POLY1305,
+        CipherSuite::Aes256Gcm => capability::AES_256_GCM,
+    }
+}
+
+fn parse_session(buf: &[u8]) -> Session {
+    Session {
+        version: buf[0],
+        capabilities: u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]),
+    }
+}
+
+fn compute_mac(key: &[u8], label: &[u8], parts: &[&[u8]]) -> [u8; MAC_SIZE] {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    hmac.input(label);
+    for part in parts {
+        hmac.input(part);
+    }
+
+    let mut mac = [0u8; MAC_SIZE];
+    hmac.raw_result(&mut mac);
+    mac
+}
+
+fn verify_mac(key: &[u8], label: &[u8], parts: &[&[u8]], mac: &[u8]) -> bool {
+    fixed_time_eq(&compute_mac(key, label, parts), mac)
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+    use futures::io::{AsyncRead, AsyncWrite};
+    use futures::stream::StreamExt;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    const ALICE_KEY: &[u8] = b"alice key";
+    const BOB_KEY: &[u8] = b"bob key";
+
+    // One direction of an in-memory connection, each write is one message.
+    struct PipeWriter(UnboundedSender<Vec<u8>>);
+
+    struct PipeReader {
+        rx: UnboundedReceiver<Vec<u8>>,
+        buf: Vec<u8>,
+    }
+
+    impl PipeReader {
+        fn new(rx: UnboundedReceiver<Vec<u8>>) -> Self {
+            Self {
+                rx,
+                buf: Vec::new(),
+            }
+        }
+    }
+
+    impl AsyncWrite for PipeWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            match self.0.unbounded_send(buf.to_vec()) {
+                Ok(_) => Poll::Ready(Ok(buf.len())),
+                Err(_) => Poll::Ready(Err(ErrorKind::BrokenPipe.into())),
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            self.0.close_channel();
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncRead for PipeReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            if self.buf.is_empty() {
+                match self.rx.poll_next_unpin(cx) {
+                    Poll::Ready(Some(data)) => self.buf = data,
+                    Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+
+            let n = buf.len().min(self.buf.len());
+            buf[..n].copy_from_slice(&self.buf[..n]);
+            self.buf.drain(..n);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    type ClientResult = std::io::Result<(Session, Cryptor, Cryptor)>;
+    type ServerResult = std::io::Result<(String, Session, Cryptor, Cryptor)>;
+
+    fn users() -> UserTable {
+        let mut users = UserTable::new();
+        users.add_user(String::from("alice"), ALICE_KEY.to_vec());
+        users.add_user(String::from("bob"), BOB_KEY.to_vec());
+        users
+    }
+
+    // Runs a client with `key` against a server of users(), passing every
+    // message through `tamper` along with its number, counted from 0 in the
+    // order they are sent.
+    fn exchange(
+        key: &[u8],
+        replay_cache: &ReplayCache,
+        mut tamper: impl FnMut(usize, &mut Vec<u8>),
+    ) -> (ClientResult, ServerResult) {
+        let users = users();
+        let (client_writer, mut from_client) = {
+            let (tx, rx) = unbounded();
+            (PipeWriter(tx), rx)
+        };
+        let (to_server, server_reader) = {
+            let (tx, rx) = unbounded();
+            (tx, PipeReader::new(rx))
+        };
+        let (server_writer, mut from_server) = {
+            let (tx, rx) = unbounded();
+            (PipeWriter(tx), rx)
+        };
+        let (to_client, client_reader) = {
+            let (tx, rx) = unbounded();
+            (tx, PipeReader::new(rx))
+        };
+
+        let client = async move {
+            let (mut reader, mut writer) = (client_reader, client_writer);
+            let suite = CipherSuite::default();
+            client_handshake(key, suite, capability::SUPPORTED, &mut reader, &mut writer).await
+        };
+
+        let server = async {
+            let (mut reader, mut writer) = (server_reader, server_writer);
+            let suite = CipherSuite::default();
+            let capabilities = capability::SUPPORTED;
+            server_handshake(
+                &users,
+                suite,
+                capabilities,
+                replay_cache,
+                &mut reader,
+                &mut writer,
+            )
+            .await
+        };
+
+        // Messages alternate between the sides, a side that gives up ends
+        // the relay and so the other side's reads.
+        let relay = async move {
+            for i in 0.. {
+                let (rx, tx) = if i % 2 == 0 {
+                    (&mut from_client, &to_server)
+                } else {
+                    (&mut from_server, &to_client)
+                };
+
+                match rx.next().await {
+                    Some(mut message) => {
+                        tamper(i, &mut message);
+                        let _ = tx.unbounded_send(message);
+                    }
+                    None => break,
+                }
+            }
+        };
+
+        task::block_on(async {
+            let ((client, server), _) = client.join(server).join(relay).await;
+            (client, server)
+        })
+    }
+
+    // A client hello as client_handshake writes it, sent at `timestamp`.
+    fn client_hello(key: &[u8], timestamp: u64) -> Vec<u8> {
+        let exchange = KeyExchange::new();
+        let key_id = compute_mac(key, KEY_ID_LABEL, &[exchange.public_key()]);
+
+        let mut hello = Vec::new();
+        hello.extend_from_slice(exchange.public_key());
+        hello.extend_from_slice(&key_id[..KEY_ID_SIZE]);
+        hello.extend_from_slice(&timestamp.to_be_bytes());
+        hello.push(PROTOCOL_VERSION);
+        let offered = capability::SUPPORTED | capability::CHACHA20_POLY1305;
+        hello.extend_from_slice(&offered.to_be_bytes());
+        let mac = compute_mac(key, CLIENT_HELLO_LABEL, &[&hello]);
+        hello.extend_from_slice(&mac);
+        hello
+    }
+
+    // Feeds the server `input`, which is over before the client finish.
+    fn serve(input: &[u8], replay_cache: &ReplayCache) -> ServerResult {
+        let users = users();
+        let mut reader = input;
+        let mut writer = Vec::new();
+        let suite = CipherSuite::default();
+
+        task::block_on(server_handshake(
+            &users,
+            suite,
+            capability::SUPPORTED,
+            replay_cache,
+            &mut reader,
+            &mut writer,
+        ))
+    }
+
+    fn assert_rejected<T>(result: std::io::Result<T>, reason: &str) {
+        match result {
+            Ok(_) => panic!("handshake accepted, expected {}", reason),
+            Err(err) => {
+                assert_eq!(err.kind(), ErrorKind::InvalidData);
+                assert_eq!(err.to_string(), reason);
+            }
+        }
+    }
+
+    #[test]
+    fn handshake() {
+        let replay_cache = ReplayCache::new();
+        let (client, server) = exchange(BOB_KEY, &replay_cache, |_, _| {});
+
+        let (client_session, mut client_encryptor, mut client_decryptor) = client.unwrap();
+        let (id, server_session, mut server_encryptor, mut server_decryptor) = server.unwrap();
+
+        assert_eq!(id, "bob");
+        assert_eq!(client_session.version, server_session.version);
+        assert_eq!(client_session.capabilities, server_session.capabilities);
+        assert!(client_session.supports(capability::CHACHA20_POLY1305));
+        assert!(!client_session.supports(capability::AES_256_GCM));
+
+        let sealed = client_encryptor.encrypt(&[], b"to server");
+        assert_eq!(
+            server_decryptor.decrypt(&[], &sealed).unwrap(),
+            b"to server"
+        );
+        let sealed = server_encryptor.encrypt(&[], b"to client");
+        assert_eq!(
+            client_decryptor.decrypt(&[], &sealed).unwrap(),
+            b"to client"
+        );
+    }
+
+    #[test]
+    fn replayed_client_hello() {
+        let replay_cache = ReplayCache::new();
+        let mut hello = Vec::new();
+        let (client, server) = exchange(ALICE_KEY, &replay_cache, |i, message| {
+            if i == 0 {
+                hello = message.clone();
+            }
+        });
+        assert!(client.is_ok() && server.is_ok());
+
+        assert_rejected(serve(&hello, &replay_cache), "client hello replayed");
+
+        // A new hello of the same user is still accepted.
+        let (client, server) = exchange(ALICE_KEY, &replay_cache, |_, _| {});
+        assert!(client.is_ok() && server.is_ok());
+    }
+
+    #[test]
+    fn client_hello_time_skew() {
+        let replay_cache = ReplayCache::new();
+        let now = unix_timestamp();
+
+        for timestamp in [now - MAX_TIME_SKEW_SECS - 10, now + MAX_TIME_SKEW_SECS + 10] {
+            let hello = client_hello(ALICE_KEY, timestamp);
+            assert_rejected(serve(&hello, &replay_cache), "client hello expired");
+        }
+
+        // Within the skew the server answers and waits for the finish.
+        let hello = client_hello(ALICE_KEY, now - MAX_TIME_SKEW_SECS / 2);
+        let err = serve(&hello, &replay_cache).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn unknown_key() {
+        let replay_cache = ReplayCache::new();
+        let (client, server) = exchange(b"mallory key", &replay_cache, |_, _| {});
+        assert!(client.is_err());
+        assert_rejected(server, "unknown client key");
+    }
+
+    #[test]
+    fn tampered_client_hello() {
+        let replay_cache = ReplayCache::new();
+        let (client, server) = exchange(ALICE_KEY, &replay_cache, |i, message| {
+            if i == 0 {
+                // The capabilities, covered by the mac only.
+                message[CLIENT_HELLO_SIZE - MAC_SIZE - 1] ^= 1;
+            }
+        });
+        assert!(client.is_err());
+        assert_rejected(server, "bad client hello");
+    }
+
+    #[test]
+    fn tampered_server_hello() {
+        for at in [0, PUBLIC_KEY_SIZE, SERVER_HELLO_SIZE - 1] {
+            let replay_cache = ReplayCache::new();
+            let (client, server) = exchange(ALICE_KEY, &replay_cache, |i, message| {
+                if i == 1 {
+                    message[at] ^= 1;
+                }
+            });
+            assert_rejected(client, "bad server hello");
+            assert_eq!(server.err().unwrap().kind(), ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn tampered_client_finish() {
+        let replay_cache = ReplayCache::new();
+        let (client, server) = exchange(ALICE_KEY, &replay_cache, |i, message| {
+            if i == 2 {
+                message[0] ^= 1;
+            }
+        });
+        assert!(client.is_ok());
+        assert_rejected(server, "bad client finish");
+    }
+
+    #[test]
+    fn truncated_client_hello() {
+        let replay_cache = ReplayCache::new();
+        let hello = client_hello(ALICE_KEY, unix_timestamp());
+        let err = serve(&hello[..CLIENT_HELLO_SIZE - 1], &replay_cache)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -2,9 +2,13 @@
 extern crate log;
 
 pub mod client;
+pub mod compression;
 pub mod cryptor;
+pub mod dns;
+pub mod handshake;
 pub mod logger;
 pub mod proxy;
+mod scheduler;
 pub mod server;
 pub mod timer;
 pub mod ucp;
@@ -51,13 +55,19 @@ mod util {
 }
 
 mod protocol {
+    use super::compression::{compress, decompress, CompressionMetrics};
+    use super::cryptor::{Cryptor, TAG_SIZE};
+    use async_std::io::Read;
+    use async_std::prelude::*;
+    use std::fmt;
     use std::net::SocketAddr;
     use std::str::from_utf8;
     use std::vec::Vec;
 
-    pub const VERIFY_DATA: [u8; 8] = [0xF0u8, 0xEF, 0xE, 0x2, 0xAE, 0xBC, 0x8C, 0x78];
     pub const HEARTBEAT_INTERVAL_MS: u64 = 5000;
     pub const ALIVE_TIMEOUT_TIME_MS: u128 = 60000;
+    pub const CONNECT_TIMEOUT_MS: u64 = 30000;
+    pub const BIND_TIMEOUT_MS: u64 = 120000;
 
     pub mod cs {
         pub const OPEN_PORT: u8 = 1;
@@ -68,6 +78,12 @@ mod protocol {
         pub const DATA: u8 = 7;
         pub const HEARTBEAT: u8 = 8;
         pub const UDP_ASSOCIATE: u8 = 9;
+        pub const REKEY: u8 = 10;
+        pub const PADDING: u8 = 11;
+        pub const WINDOW_UPDATE: u8 = 12;
+        pub const RESOLVE: u8 = 13;
+        pub const LISTEN: u8 = 14;
+        pub const BIND: u8 = 15;
     }
 
     pub mod sc {
@@ -76,119 +92,507 @@ mod protocol {
         pub const CONNECT_OK: u8 = 4;
         pub const DATA: u8 = 5;
         pub const HEARTBEAT_RSP: u8 = 6;
+        pub const REKEY: u8 = 7;
+        pub const PADDING: u8 = 8;
+        pub const WINDOW_UPDATE: u8 = 9;
+        pub const CONNECT_ERR: u8 = 10;
+        pub const OPEN_PORT: u8 = 11;
     }
 
-    fn write_cmd_id_len(buf: &mut [u8], cmd: u8, id: u32, len: u32) {
-        buf[0] = cmd;
-        unsafe {
-            *(buf.as_ptr().offset(1) as *mut u32) = id.to_be();
-            *(buf.as_ptr().offset(5) as *mut u32) = len.to_be();
+    // Ports the server opens for connections to a LISTEN of the client have
+    // this bit set in their id, so they never collide with the client's.
+    pub const REMOTE_PORT_ID: u32 = 0x8000_0000;
+
+    // Set on the DATA command when the payload is deflated.
+    pub const COMPRESSED: u8 = 0x80;
+
+    pub const MAX_PADDING_SIZE: usize = 1024;
+    // Large enough for a UDP datagram with its address.
+    pub const MAX_FRAME_SIZE: usize = 128 * 1024;
+
+    // Bytes of DATA a port may have in flight, the receiver grants more with
+    // WINDOW_UPDATE once a quarter of the window has been consumed.
+    pub const INITIAL_WINDOW: u32 = 256 * 1024;
+    pub const WINDOW_UPDATE_THRESHOLD: u32 = INITIAL_WINDOW / 4;
+
+    // Why the server could not connect a port, sent with CONNECT_ERR.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum ConnectError {
+        General,
+        Refused,
+        Unreachable,
+        DnsFailure,
+        Timeout,
+        Denied,
+    }
+
+    impl ConnectError {
+        pub fn from_io_error(err: &std::io::Error) -> ConnectError {
+            use std::io::ErrorKind::*;
+
+            match err.kind() {
+                ConnectionRefused => ConnectError::Refused,
+                NetworkUnreachable | HostUnreachable | AddrNotAvailable => {
+                    ConnectError::Unreachable
+                }
+                TimedOut => ConnectError::Timeout,
+                PermissionDenied => ConnectError::Denied,
+                _ => ConnectError::General,
+            }
+        }
+
+        fn code(self) -> u8 {
+            match self {
+                ConnectError::General => 1,
+                ConnectError::Refused => 2,
+                ConnectError::Unreachable => 3,
+                ConnectError::DnsFailure => 4,
+                ConnectError::Timeout => 5,
+                ConnectError::Denied => 6,
+            }
+        }
+
+        // Codes of newer peers read as a general failure.
+        fn from_code(code: u8) -> ConnectError {
+            match code {
+                2 => ConnectError::Refused,
+                3 => ConnectError::Unreachable,
+                4 => ConnectError::DnsFailure,
+                5 => ConnectError::Timeout,
+                6 => ConnectError::Denied,
+                _ => ConnectError::General,
+            }
         }
     }
 
-    fn pack_cmd_id_msg(cmd: u8, id: u32) -> [u8; 5] {
-        let mut buf = [0u8; 5];
-        buf[0] = cmd;
-        unsafe {
-            *(buf.as_ptr().offset(1) as *mut u32) = id.to_be();
+    impl fmt::Display for ConnectError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let reason = match self {
+                ConnectError::General => "general failure",
+                ConnectError::Refused => "connection refused",
+                ConnectError::Unreachable => "unreachable",
+                ConnectError::DnsFailure => "dns failure",
+                ConnectError::Timeout => "timed out",
+                ConnectError::Denied => "denied by policy",
+            };
+
+            write!(f, "{}", reason)
         }
-        buf
     }
 
-    fn pack_cmd_id_data_msg(cmd: u8, id: u32, data: &[u8]) -> Vec<u8> {
-        let mut buf = vec![0; 9 + data.len()];
-        let len = data.len() as u32;
+    const FRAME_LEN_SIZE: usize = 4;
+    const FRAME_HEADER_SIZE: usize = 5;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Direction {
+        ClientToServer,
+        ServerToClient,
+    }
 
-        write_cmd_id_len(&mut buf, cmd, id, len);
-        buf[9..].copy_from_slice(data);
+    #[derive(Debug, PartialEq)]
+    pub enum Frame {
+        OpenPort(u32),
+        ClosePort(u32),
+        ShutdownWrite(u32),
+        Connect(u32, Vec<u8>),
+        ConnectDomainName(u32, Vec<u8>, u16),
+        UdpAssociate(u32, Vec<u8>),
+        Resolve(u32, Vec<u8>),
+        Listen(u32, Vec<u8>),
+        Bind(u32, Vec<u8>),
+        // Port id, id of the listening port and the peer address.
+        RemoteOpenPort(u32, u32, Vec<u8>),
+        ConnectOk(u32, Vec<u8>),
+        ConnectErr(u32, ConnectError),
+        Data(u32, Vec<u8>),
+        CompressedData(u32, Vec<u8>),
+        Heartbeat,
+        HeartbeatRsp,
+        Rekey,
+        Padding(usize),
+        WindowUpdate(u32, u32),
+    }
 
-        buf
+    #[derive(Debug)]
+    pub enum FrameError {
+        Io(std::io::Error),
+        Corrupted,
+        TooLarge(usize),
+        Truncated(u8),
+        Malformed(u8),
+        UnknownCommand(u8),
+        WrongDirection,
     }
 
-    pub fn pack_cs_open_port_msg(id: u32) -> [u8; 5] {
-        pack_cmd_id_msg(cs::OPEN_PORT, id)
+    impl fmt::Display for FrameError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                FrameError::Io(err) => write!(f, "{}", err),
+                FrameError::Corrupted => write!(f, "corrupted frame"),
+                FrameError::TooLarge(len) => write!(f, "frame of {} bytes too large", len),
+                FrameError::Truncated(cmd) => write!(f, "truncated frame: {}", cmd),
+                FrameError::Malformed(cmd) => write!(f, "malformed frame: {}", cmd),
+                FrameError::UnknownCommand(cmd) => write!(f, "unknown operation: {}", cmd),
+                FrameError::WrongDirection => write!(f, "frame sent in wrong direction"),
+            }
+        }
     }
 
-    pub fn pack_cs_connect_msg(id: u32, data: &[u8]) -> Vec<u8> {
-        pack_cmd_id_data_msg(cs::CONNECT, id, data)
+    impl std::error::Error for FrameError {}
+
+    impl From<std::io::Error> for FrameError {
+        fn from(err: std::io::Error) -> Self {
+            FrameError::Io(err)
+        }
+    }
+
+    impl From<FrameError> for std::io::Error {
+        fn from(err: FrameError) -> Self {
+            match err {
+                FrameError::Io(err) => err,
+                err => std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+            }
+        }
+    }
+
+    impl Frame {
+        fn command(&self, direction: Direction) -> Option<u8> {
+            use Direction::*;
+
+            let cmd = match (self, direction) {
+                (Frame::OpenPort(_), ClientToServer) => cs::OPEN_PORT,
+                (Frame::ClosePort(_), ClientToServer) => cs::CLOSE_PORT,
+                (Frame::ShutdownWrite(_), ClientToServer) => cs::SHUTDOWN_WRITE,
+                (Frame::Connect(..), ClientToServer) => cs::CONNECT,
+                (Frame::ConnectDomainName(..), ClientToServer) => cs::CONNECT_DOMAIN_NAME,
+                (Frame::UdpAssociate(..), ClientToServer) => cs::UDP_ASSOCIATE,
+                (Frame::Resolve(..), ClientToServer) => cs::RESOLVE,
+                (Frame::Listen(..), ClientToServer) => cs::LISTEN,
+                (Frame::Bind(..), ClientToServer) => cs::BIND,
+                (Frame::Data(..), ClientToServer) => cs::DATA,
+                (Frame::CompressedData(..), ClientToServer) => cs::DATA | COMPRESSED,
+                (Frame::Heartbeat, ClientToServer) => cs::HEARTBEAT,
+                (Frame::Rekey, ClientToServer) => cs::REKEY,
+                (Frame::Padding(_), ClientToServer) => cs::PADDING,
+                (Frame::WindowUpdate(..), ClientToServer) => cs::WINDOW_UPDATE,
+
+                (Frame::RemoteOpenPort(..), ServerToClient) => sc::OPEN_PORT,
+                (Frame::ClosePort(_), ServerToClient) => sc::CLOSE_PORT,
+                (Frame::ShutdownWrite(_), ServerToClient) => sc::SHUTDOWN_WRITE,
+                (Frame::ConnectOk(..), ServerToClient) => sc::CONNECT_OK,
+                (Frame::ConnectErr(..), ServerToClient) => sc::CONNECT_ERR,
+                (Frame::Data(..), ServerToClient) => sc::DATA,
+                (Frame::CompressedData(..), ServerToClient) => sc::DATA | COMPRESSED,
+                (Frame::HeartbeatRsp, ServerToClient) => sc::HEARTBEAT_RSP,
+                (Frame::Rekey, ServerToClient) => sc::REKEY,
+                (Frame::Padding(_), ServerToClient) => sc::PADDING,
+                (Frame::WindowUpdate(..), ServerToClient) => sc::WINDOW_UPDATE,
+
+                _ => return None,
+            };
+
+            Some(cmd)
+        }
+
+        fn id(&self) -> u32 {
+            match self {
+                Frame::OpenPort(id)
+                | Frame::ClosePort(id)
+                | Frame::ShutdownWrite(id)
+                | Frame::Connect(id, _)
+                | Frame::ConnectDomainName(id, _, _)
+                | Frame::UdpAssociate(id, _)
+                | Frame::Resolve(id, _)
+                | Frame::Listen(id, _)
+                | Frame::Bind(id, _)
+                | Frame::RemoteOpenPort(id, _, _)
+                | Frame::ConnectOk(id, _)
+                | Frame::ConnectErr(id, _)
+                | Frame::Data(id, _)
+                | Frame::CompressedData(id, _)
+                | Frame::WindowUpdate(id, _) => *id,
+                _ => 0,
+            }
+        }
+
+        // A frame is the command, port id and payload.
+        pub fn encode(&self, direction: Direction) -> Result<Vec<u8>, FrameError> {
+            let cmd = self.command(direction).ok_or(FrameError::WrongDirection)?;
+
+            let mut buf = Vec::with_capacity(FRAME_HEADER_SIZE);
+            buf.push(cmd);
+            buf.extend_from_slice(&self.id().to_be_bytes());
+
+            match self {
+                Frame::Connect(_, data)
+                | Frame::UdpAssociate(_, data)
+                | Frame::Resolve(_, data)
+                | Frame::Listen(_, data)
+                | Frame::Bind(_, data)
+                | Frame::ConnectOk(_, data)
+                | Frame::Data(_, data)
+                | Frame::CompressedData(_, data) => buf.extend_from_slice(data),
+
+                Frame::ConnectDomainName(_, domain, port) => {
+                    buf.extend_from_slice(domain);
+                    buf.extend_from_slice(&port.to_be_bytes());
+                }
+
+                Frame::RemoteOpenPort(_, listen_id, peer) => {
+                    buf.extend_from_slice(&listen_id.to_be_bytes());
+                    buf.extend_from_slice(peer);
+                }
+
+                Frame::ConnectErr(_, err) => buf.push(err.code()),
+                Frame::Padding(len) => buf.resize(FRAME_HEADER_SIZE + len, 0),
+                Frame::WindowUpdate(_, credit) => buf.extend_from_slice(&credit.to_be_bytes()),
+                _ => {}
+            }
+
+            if buf.len() > MAX_FRAME_SIZE {
+                return Err(FrameError::TooLarge(buf.len()));
+            }
+
+            Ok(buf)
+        }
+
+        pub fn decode(direction: Direction, mut buf: Vec<u8>) -> Result<Frame, FrameError> {
+            if buf.len() < FRAME_HEADER_SIZE {
+                return Err(FrameError::Truncated(buf.first().copied().unwrap_or(0)));
+            }
+
+            let cmd = buf[0];
+            let id = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+            buf.drain(..FRAME_HEADER_SIZE);
+
+            let frame = match direction {
+                Direction::ClientToServer => match cmd {
+                    cs::OPEN_PORT => Frame::OpenPort(id),
+                    cs::CLOSE_PORT => Frame::ClosePort(id),
+                    cs::SHUTDOWN_WRITE => Frame::ShutdownWrite(id),
+                    cs::CONNECT => Frame::Connect(id, check_address(cmd, buf)?),
+                    cs::CONNECT_DOMAIN_NAME => {
+                        if buf.len() < 2 {
+                            return Err(FrameError::Truncated(cmd));
+                        }
+
+                        let pos = buf.len() - 2;
+                        let port = u16::from_be_bytes([buf[pos], buf[pos + 1]]);
+                        buf.truncate(pos);
+                        Frame::ConnectDomainName(id, check_address(cmd, buf)?, port)
+                    }
+                    cs::UDP_ASSOCIATE => Frame::UdpAssociate(id, check_address(cmd, buf)?),
+                    cs::RESOLVE => Frame::Resolve(id, check_address(cmd, buf)?),
+                    cs::LISTEN => Frame::Listen(id, check_address(cmd, buf)?),
+                    cs::BIND => Frame::Bind(id, check_address(cmd, buf)?),
+                    cs::DATA => Frame::Data(id, buf),
+                    cmd if cmd == cs::DATA | COMPRESSED => Frame::CompressedData(id, buf),
+                    cs::HEARTBEAT => Frame::Heartbeat,
+                    cs::REKEY => Frame::Rekey,
+                    cs::PADDING => Frame::Padding(buf.len()),
+                    cs::WINDOW_UPDATE => Frame::WindowUpdate(id, parse_credit(cmd, &buf)?),
+                    _ => return Err(FrameError::UnknownCommand(cmd)),
+                },
+
+                Direction::ServerToClient => match cmd {
+                    sc::OPEN_PORT => {
+                        if buf.len() < 4 {
+                            return Err(FrameError::Truncated(cmd));
+                        }
+
+                        let listen_id = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+                        buf.drain(..4);
+                        Frame::RemoteOpenPort(id, listen_id, check_address(cmd, buf)?)
+                    }
+                    sc::CLOSE_PORT => Frame::ClosePort(id),
+                    sc::SHUTDOWN_WRITE => Frame::ShutdownWrite(id),
+                    sc::CONNECT_OK => Frame::ConnectOk(id, buf),
+                    sc::CONNECT_ERR => match buf[..] {
+                        [code] => Frame::ConnectErr(id, ConnectError::from_code(code)),
+                        _ => return Err(FrameError::Malformed(cmd)),
+                    },
+                    sc::DATA => Frame::Data(id, buf),
+                    cmd if cmd == sc::DATA | COMPRESSED => Frame::CompressedData(id, buf),
+                    sc::HEARTBEAT_RSP => Frame::HeartbeatRsp,
+                    sc::REKEY => Frame::Rekey,
+                    sc::PADDING => Frame::Padding(buf.len()),
+                    sc::WINDOW_UPDATE => Frame::WindowUpdate(id, parse_credit(cmd, &buf)?),
+                    _ => return Err(FrameError::UnknownCommand(cmd)),
+                },
+            };
+
+            Ok(frame)
+        }
     }
 
-    pub fn pack_cs_connect_domain_msg(id: u32, domain: &[u8], port: u16) -> Vec<u8> {
-        let buf_len = 11 + domain.len();
-        let mut buf = vec![0; buf_len];
-        let len = domain.len() as u32 + 2;
+    // Deflates the payload when that makes it smaller, the flag on each frame
+    // lets incompressible data go as is.
+    pub fn data_frame(id: u32, buf: Vec<u8>, metrics: Option<&CompressionMetrics>) -> Frame {
+        let metrics = match metrics {
+            Some(metrics) => metrics,
+            None => return Frame::Data(id, buf),
+        };
+
+        match compress(&buf) {
+            Some(compressed) => {
+                metrics.add(buf.len(), compressed.len());
+                Frame::CompressedData(id, compressed)
+            }
+
+            None => {
+                metrics.add(buf.len(), buf.len());
+                Frame::Data(id, buf)
+            }
+        }
+    }
 
-        write_cmd_id_len(&mut buf, cs::CONNECT_DOMAIN_NAME, id, len);
-        buf[9..buf_len - 2].copy_from_slice(domain);
+    // Returns the payload of a received DATA frame, inflated if need be.
+    pub fn data_payload(
+        frame: Frame,
+        metrics: Option<&CompressionMetrics>,
+    ) -> Result<(u32, Vec<u8>), FrameError> {
+        let (id, wire_len, data) = match frame {
+            Frame::Data(id, buf) => (id, buf.len(), buf),
+            Frame::CompressedData(id, buf) if metrics.is_some() => {
+                let data =
+                    decompress(&buf, MAX_FRAME_SIZE).ok_or(FrameError::Malformed(COMPRESSED))?;
+                (id, buf.len(), data)
+            }
+            _ => return Err(FrameError::Malformed(COMPRESSED)),
+        };
 
-        unsafe {
-            let offset = (buf_len - 2) as isize;
-            *(buf.as_ptr().offset(offset) as *mut u16) = port.to_be();
+        if let Some(metrics) = metrics {
+            metrics.add(data.len(), wire_len);
         }
 
-        buf
+        Ok((id, data))
     }
 
-    pub fn pack_udp_associate_msg(id: u32, data: &[u8]) -> Vec<u8> {
-        pack_cmd_id_data_msg(cs::UDP_ASSOCIATE, id, data)
+    fn parse_credit(cmd: u8, buf: &[u8]) -> Result<u32, FrameError> {
+        match buf {
+            [a, b, c, d] => Ok(u32::from_be_bytes([*a, *b, *c, *d])),
+            _ => Err(FrameError::Malformed(cmd)),
+        }
     }
 
-    pub fn pack_cs_shutdown_write_msg(id: u32) -> [u8; 5] {
-        pack_cmd_id_msg(cs::SHUTDOWN_WRITE, id)
+    fn check_address(cmd: u8, buf: Vec<u8>) -> Result<Vec<u8>, FrameError> {
+        match from_utf8(&buf) {
+            Ok(_) => Ok(buf),
+            Err(_) => Err(FrameError::Malformed(cmd)),
+        }
     }
 
-    pub fn pack_cs_data_msg(id: u32, data: &[u8]) -> Vec<u8> {
-        pack_cmd_id_data_msg(cs::DATA, id, data)
+    // A frame goes on the wire as its sealed length followed by the sealed
+    // frame, so only ciphertext of varying length is visible.
+    fn pack_frame(
+        frame: &Frame,
+        direction: Direction,
+        encryptor: &mut Cryptor,
+    ) -> Result<Vec<u8>, FrameError> {
+        let frame = frame.encode(direction)?;
+        let len = frame.len() as u32;
+
+        let mut buf = encryptor.encrypt(&[], &len.to_be_bytes());
+        buf.extend_from_slice(&encryptor.encrypt(&[], &frame));
+
+        Ok(buf)
     }
 
-    pub fn pack_cs_close_port_msg(id: u32) -> [u8; 5] {
-        pack_cmd_id_msg(cs::CLOSE_PORT, id)
+    async fn read_frame<R: Read + Unpin>(
+        stream: &mut R,
+        direction: Direction,
+        decryptor: &mut Cryptor,
+    ) -> Result<Frame, FrameError> {
+        let mut buf = [0u8; FRAME_LEN_SIZE + TAG_SIZE];
+        stream.read_exact(&mut buf).await?;
+
+        let len = decryptor.decrypt(&[], &buf).ok_or(FrameError::Corrupted)?;
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(FrameError::TooLarge(len));
+        }
+
+        let mut buf = vec![0u8; len + TAG_SIZE];
+        stream.read_exact(&mut buf).await?;
+
+        let frame = decryptor.decrypt(&[], &buf).ok_or(FrameError::Corrupted)?;
+        Frame::decode(direction, frame)
     }
 
-    pub fn pack_cs_heartbeat_msg() -> [u8; 1] {
-        let buf = [cs::HEARTBEAT];
-        buf
+    pub fn pack_cs_frame(frame: &Frame, encryptor: &mut Cryptor) -> Result<Vec<u8>, FrameError> {
+        pack_frame(frame, Direction::ClientToServer, encryptor)
     }
 
-    pub fn pack_sc_close_port_msg(id: u32) -> [u8; 5] {
-        pack_cmd_id_msg(sc::CLOSE_PORT, id)
+    pub fn pack_sc_frame(frame: &Frame, encryptor: &mut Cryptor) -> Result<Vec<u8>, FrameError> {
+        pack_frame(frame, Direction::ServerToClient, encryptor)
     }
 
-    pub fn pack_sc_shutdown_write_msg(id: u32) -> [u8; 5] {
-        pack_cmd_id_msg(sc::SHUTDOWN_WRITE, id)
+    pub async fn read_cs_frame<R: Read + Unpin>(
+        stream: &mut R,
+        decryptor: &mut Cryptor,
+    ) -> Result<Frame, FrameError> {
+        read_frame(stream, Direction::ClientToServer, decryptor).await
     }
 
-    pub fn pack_sc_connect_ok_msg(id: u32, data: &[u8]) -> Vec<u8> {
-        pack_cmd_id_data_msg(sc::CONNECT_OK, id, data)
+    pub async fn read_sc_frame<R: Read + Unpin>(
+        stream: &mut R,
+        decryptor: &mut Cryptor,
+    ) -> Result<Frame, FrameError> {
+        read_frame(stream, Direction::ServerToClient, decryptor).await
     }
 
-    pub fn pack_sc_data_msg(id: u32, data: &[u8]) -> Vec<u8> {
-        pack_cmd_id_data_msg(sc::DATA, id, data)
+    // Decides when to send padding frames of random size, so frame sizes and
+    // timing do not mirror the application. Padding stays within `budget`
+    // percent of the data sent.
+    pub struct Padding {
+        budget: u64,
+        data_bytes: u64,
+        padding_bytes: u64,
     }
 
-    pub fn pack_sc_heartbeat_rsp_msg() -> [u8; 1] {
-        let buf = [sc::HEARTBEAT_RSP];
-        buf
+    impl Padding {
+        pub fn new(budget: u32) -> Self {
+            Padding {
+                budget: budget as u64,
+                data_bytes: 0,
+                padding_bytes: 0,
+            }
+        }
+
+        pub fn add_data(&mut self, len: usize) {
+            self.data_bytes += len as u64;
+        }
+
+        // Returns the size of the padding frame to send now, if any.
+        pub fn next_padding(&mut self) -> Option<usize> {
+            if !rand::random::<u8>().is_multiple_of(4) {
+                return None;
+            }
+
+            let len = rand::random::<u32>() as usize % MAX_PADDING_SIZE + 1;
+            let allowed = self.data_bytes * self.budget / 100;
+            if self.padding_bytes + len as u64 > allowed {
+                return None;
+            }
+
+            self.padding_bytes += len as u64;
+            Some(len)
+        }
     }
 
     pub struct UdpDataPacker;
 
     impl UdpDataPacker {
         pub fn pack_udp_data(&self, data: &[u8], addr: &SocketAddr) -> Vec<u8> {
-            let mut addr_buf = Vec::new();
-            let _ = std::io::Write::write_fmt(&mut addr_buf, format_args!("{}", addr));
-
-            let mut buf = vec![0; 4 + data.len()];
-            let len = (data.len() + addr_buf.len()) as u16;
+            let addr = addr.to_string();
+            let len = (data.len() + addr.len()) as u16;
             let data_len = data.len() as u16;
 
-            unsafe {
-                *(buf.as_ptr() as *mut u16) = len.to_be();
-                *(buf.as_ptr().offset(2) as *mut u16) = data_len.to_be();
-            }
-
-            buf[4..].copy_from_slice(data);
-            buf.append(&mut addr_buf);
+            let mut buf = Vec::with_capacity(4 + len as usize);
+            buf.extend_from_slice(&len.to_be_bytes());
+            buf.extend_from_slice(&data_len.to_be_bytes());
+            buf.extend_from_slice(data);
+            buf.extend_from_slice(addr.as_bytes());
 
             buf
         }
@@ -207,41 +611,342 @@ mod protocol {
             self.buffer.append(&mut buf)
         }
 
-        pub fn unpack_udp_data(&mut self) -> Option<(Vec<u8>, SocketAddr)> {
-            let (len, _) = self.unpack_udp_data_length(&self.buffer)?;
-            let total_len = 4 + len;
+        // Returns Ok(None) until a whole datagram is buffered.
+        pub fn unpack_udp_data(&mut self) -> Result<Option<(Vec<u8>, SocketAddr)>, FrameError> {
+            if self.buffer.len() < 4 {
+                return Ok(None);
+            }
 
+            let len = u16::from_be_bytes([self.buffer[0], self.buffer[1]]) as usize;
+            let data_len = u16::from_be_bytes([self.buffer[2], self.buffer[3]]) as usize;
+            if data_len > len {
+                return Err(FrameError::Malformed(cs::DATA));
+            }
+
+            let total_len = 4 + len;
             if self.buffer.len() < total_len {
-                return None;
+                return Ok(None);
             }
 
-            let udp_data = self.do_unpack_udp_data(&self.buffer);
+            let data = self.buffer[4..4 + data_len].to_vec();
+            let addr = from_utf8(&self.buffer[4 + data_len..total_len])
+                .ok()
+                .and_then(|addr| addr.parse().ok())
+                .ok_or(FrameError::Malformed(cs::DATA))?;
+
             self.buffer.drain(0..total_len);
-            Some(udp_data)
+            Ok(Some((data, addr)))
         }
+    }
 
-        fn unpack_udp_data_length(&self, buf: &[u8]) -> Option<(usize, usize)> {
-            if buf.len() < 4 {
-                return None;
+    impl Default for UdpDataUnpacker {
+        fn default() -> Self {
+            UdpDataUnpacker::new()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::super::cryptor::{CipherSuite, KEY_SIZE};
+        use super::*;
+        use async_std::task;
+        use Direction::*;
+
+        fn cs_frames() -> Vec<Frame> {
+            vec![
+                Frame::OpenPort(1),
+                Frame::ClosePort(2),
+                Frame::ShutdownWrite(3),
+                Frame::Connect(4, b"127.0.0.1:80".to_vec()),
+                Frame::ConnectDomainName(5, b"example.com".to_vec(), 443),
+                Frame::ConnectDomainName(5, Vec::new(), 0),
+                Frame::UdpAssociate(6, b"0.0.0.0:0".to_vec()),
+                Frame::Resolve(7, b"example.com".to_vec()),
+                Frame::Listen(8, b"[::1]:8080".to_vec()),
+                Frame::Bind(9, b"10.0.0.1:21".to_vec()),
+                Frame::Data(10, b"data".to_vec()),
+                Frame::Data(10, Vec::new()),
+                Frame::CompressedData(11, vec![1, 2, 3]),
+                Frame::Heartbeat,
+                Frame::Rekey,
+                Frame::Padding(0),
+                Frame::Padding(MAX_PADDING_SIZE),
+                Frame::WindowUpdate(12, u32::MAX),
+            ]
+        }
+
+        fn sc_frames() -> Vec<Frame> {
+            let mut frames = vec![
+                Frame::RemoteOpenPort(REMOTE_PORT_ID | 1, 8, b"1.2.3.4:5".to_vec()),
+                Frame::ClosePort(2),
+                Frame::ShutdownWrite(3),
+                Frame::ConnectOk(4, b"127.0.0.1:1234".to_vec()),
+                Frame::Data(6, b"data".to_vec()),
+                Frame::CompressedData(7, vec![1, 2, 3]),
+                Frame::HeartbeatRsp,
+                Frame::Rekey,
+                Frame::Padding(16),
+                Frame::WindowUpdate(8, INITIAL_WINDOW),
+            ];
+
+            for err in [
+                ConnectError::General,
+                ConnectError::Refused,
+                ConnectError::Unreachable,
+                ConnectError::DnsFailure,
+                ConnectError::Timeout,
+                ConnectError::Denied,
+            ] {
+                frames.push(Frame::ConnectErr(5, err));
+            }
+
+            frames
+        }
+
+        fn header(cmd: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+            let mut buf = vec![cmd];
+            buf.extend_from_slice(&id.to_be_bytes());
+            buf.extend_from_slice(payload);
+            buf
+        }
+
+        #[test]
+        fn round_trip() {
+            for (direction, frames) in
+                [(ClientToServer, cs_frames()), (ServerToClient, sc_frames())]
+            {
+                for frame in frames {
+                    let buf = frame.encode(direction).unwrap();
+                    assert_eq!(Frame::decode(direction, buf).unwrap(), frame);
+                }
+            }
+        }
+
+        #[test]
+        fn wrong_direction() {
+            for frame in cs_frames() {
+                if let Frame::ClosePort(_)
+                | Frame::ShutdownWrite(_)
+                | Frame::Data(..)
+                | Frame::CompressedData(..)
+                | Frame::Rekey
+                | Frame::Padding(_)
+                | Frame::WindowUpdate(..) = frame
+                {
+                    continue;
+                }
+
+                assert!(matches!(
+                    frame.encode(ServerToClient),
+                    Err(FrameError::WrongDirection)
+                ));
+            }
+
+            for frame in [
+                Frame::RemoteOpenPort(1, 2, Vec::new()),
+                Frame::ConnectOk(1, Vec::new()),
+                Frame::ConnectErr(1, ConnectError::General),
+                Frame::HeartbeatRsp,
+            ] {
+                assert!(matches!(
+                    frame.encode(ClientToServer),
+                    Err(FrameError::WrongDirection)
+                ));
+            }
+        }
+
+        #[test]
+        fn too_large() {
+            let frame = Frame::Data(1, vec![0; MAX_FRAME_SIZE - FRAME_HEADER_SIZE]);
+            assert!(frame.encode(ClientToServer).is_ok());
+
+            let frame = Frame::Data(1, vec![0; MAX_FRAME_SIZE - FRAME_HEADER_SIZE + 1]);
+            assert!(matches!(
+                frame.encode(ClientToServer),
+                Err(FrameError::TooLarge(len)) if len == MAX_FRAME_SIZE + 1
+            ));
+        }
+
+        #[test]
+        fn truncated() {
+            for direction in [ClientToServer, ServerToClient] {
+                assert!(matches!(
+                    Frame::decode(direction, Vec::new()),
+                    Err(FrameError::Truncated(0))
+                ));
+                assert!(matches!(
+                    Frame::decode(direction, vec![cs::DATA, 0, 0, 0]),
+                    Err(FrameError::Truncated(cs::DATA))
+                ));
+            }
+
+            let buf = header(cs::CONNECT_DOMAIN_NAME, 1, &[0]);
+            assert!(matches!(
+                Frame::decode(ClientToServer, buf),
+                Err(FrameError::Truncated(cs::CONNECT_DOMAIN_NAME))
+            ));
+
+            let buf = header(sc::OPEN_PORT, 1, &[0, 0, 1]);
+            assert!(matches!(
+                Frame::decode(ServerToClient, buf),
+                Err(FrameError::Truncated(sc::OPEN_PORT))
+            ));
+        }
+
+        #[test]
+        fn malformed() {
+            for payload in [&[][..], &[1, 2]] {
+                let buf = header(sc::CONNECT_ERR, 1, payload);
+                assert!(matches!(
+                    Frame::decode(ServerToClient, buf),
+                    Err(FrameError::Malformed(sc::CONNECT_ERR))
+                ));
+            }
+
+            for payload in [&[][..], &[0, 0, 1], &[0, 0, 0, 0, 1]] {
+                let buf = header(cs::WINDOW_UPDATE, 1, payload);
+                assert!(matches!(
+                    Frame::decode(ClientToServer, buf),
+                    Err(FrameError::Malformed(cs::WINDOW_UPDATE))
+                ));
+
+                let buf = header(sc::WINDOW_UPDATE, 1, payload);
+                assert!(matches!(
+                    Frame::decode(ServerToClient, buf),
+                    Err(FrameError::Malformed(sc::WINDOW_UPDATE))
+                ));
             }
 
-            let len = u16::from_be(unsafe { *(buf.as_ptr() as *const u16) }) as usize;
-            let data_len =
-                u16::from_be(unsafe { *(buf.as_ptr().offset(2) as *const u16) }) as usize;
-            Some((len, data_len))
+            let buf = header(cs::CONNECT, 1, &[0xff, 0xfe]);
+            assert!(matches!(
+                Frame::decode(ClientToServer, buf),
+                Err(FrameError::Malformed(cs::CONNECT))
+            ));
+
+            let buf = header(sc::OPEN_PORT, 1, &[0, 0, 0, 1, 0xff]);
+            assert!(matches!(
+                Frame::decode(ServerToClient, buf),
+                Err(FrameError::Malformed(sc::OPEN_PORT))
+            ));
+
+            // Unknown error codes of newer peers are not malformed.
+            let buf = header(sc::CONNECT_ERR, 1, &[200]);
+            assert_eq!(
+                Frame::decode(ServerToClient, buf).unwrap(),
+                Frame::ConnectErr(1, ConnectError::General)
+            );
+        }
+
+        #[test]
+        fn unknown_command() {
+            assert!(matches!(
+                Frame::decode(ClientToServer, header(0x7f, 1, &[])),
+                Err(FrameError::UnknownCommand(0x7f))
+            ));
+            assert!(matches!(
+                Frame::decode(
+                    ServerToClient,
+                    header(sc::HEARTBEAT_RSP | COMPRESSED, 1, &[])
+                ),
+                Err(FrameError::UnknownCommand(_))
+            ));
         }
 
-        fn do_unpack_udp_data(&self, buf: &[u8]) -> (Vec<u8>, SocketAddr) {
-            let (len, data_len) = self.unpack_udp_data_length(buf).unwrap();
+        #[test]
+        fn sealed_round_trip() {
+            let mut encryptor = Cryptor::new(CipherSuite::default(), [7; KEY_SIZE]);
+            let mut decryptor = Cryptor::new(CipherSuite::default(), [7; KEY_SIZE]);
+
+            let mut buf = Vec::new();
+            for frame in cs_frames() {
+                buf.extend(pack_cs_frame(&frame, &mut encryptor).unwrap());
+            }
+
+            let mut stream = &buf[..];
+            for frame in cs_frames() {
+                let read = task::block_on(read_cs_frame(&mut stream, &mut decryptor)).unwrap();
+                assert_eq!(read, frame);
+            }
+            assert!(stream.is_empty());
+        }
 
-            let mut data = vec![0; data_len];
-            data.copy_from_slice(&buf[4..4 + data_len]);
+        #[test]
+        fn sealed_length_too_large() {
+            let mut encryptor = Cryptor::new(CipherSuite::default(), [7; KEY_SIZE]);
+            let mut decryptor = Cryptor::new(CipherSuite::default(), [7; KEY_SIZE]);
+
+            let len = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+            let buf = encryptor.encrypt(&[], &len);
+            let result = task::block_on(read_sc_frame(&mut &buf[..], &mut decryptor));
+            assert!(matches!(result, Err(FrameError::TooLarge(len)) if len == MAX_FRAME_SIZE + 1));
+        }
+
+        #[test]
+        fn sealed_tampered() {
+            let mut encryptor = Cryptor::new(CipherSuite::default(), [7; KEY_SIZE]);
+            let mut decryptor = Cryptor::new(CipherSuite::default(), [7; KEY_SIZE]);
+
+            let mut buf = pack_sc_frame(&Frame::Data(1, b"data".to_vec()), &mut encryptor).unwrap();
+            let last = buf.len() - 1;
+            buf[last] ^= 1;
+            let result = task::block_on(read_sc_frame(&mut &buf[..], &mut decryptor));
+            assert!(matches!(result, Err(FrameError::Corrupted)));
+        }
+
+        #[test]
+        fn udp_data_round_trip() {
+            let addrs: [SocketAddr; 2] =
+                ["1.2.3.4:53".parse().unwrap(), "[::1]:8080".parse().unwrap()];
+            let mut unpacker = UdpDataUnpacker::new();
+            for addr in &addrs {
+                unpacker.append_data(UdpDataPacker.pack_udp_data(b"datagram", addr));
+            }
+            unpacker.append_data(UdpDataPacker.pack_udp_data(b"", &addrs[0]));
+
+            for addr in &addrs {
+                let (data, from) = unpacker.unpack_udp_data().unwrap().unwrap();
+                assert_eq!((&data[..], from), (&b"datagram"[..], *addr));
+            }
+            let (data, from) = unpacker.unpack_udp_data().unwrap().unwrap();
+            assert_eq!((&data[..], from), (&b""[..], addrs[0]));
+            assert!(unpacker.unpack_udp_data().unwrap().is_none());
+        }
+
+        #[test]
+        fn udp_data_in_pieces() {
+            let buf = UdpDataPacker.pack_udp_data(b"datagram", &"1.2.3.4:53".parse().unwrap());
+            let mut unpacker = UdpDataUnpacker::default();
+
+            for byte in &buf[..buf.len() - 1] {
+                unpacker.append_data(vec![*byte]);
+                assert!(unpacker.unpack_udp_data().unwrap().is_none());
+            }
+
+            unpacker.append_data(vec![buf[buf.len() - 1]]);
+            assert!(unpacker.unpack_udp_data().unwrap().is_some());
+        }
 
-            let addr = from_utf8(&buf[4 + data_len..4 + len])
-                .unwrap()
-                .parse()
-                .unwrap();
-            (data, addr)
+        #[test]
+        fn udp_data_malformed() {
+            // Data longer than the whole datagram.
+            let mut unpacker = UdpDataUnpacker::new();
+            unpacker.append_data(vec![0, 2, 0, 3]);
+            assert!(matches!(
+                unpacker.unpack_udp_data(),
+                Err(FrameError::Malformed(cs::DATA))
+            ));
+
+            // An address that does not parse.
+            let mut unpacker = UdpDataUnpacker::new();
+            unpacker.append_data(vec![0, 5, 0, 1, b'x', b'a', b':', b'1', 0xff]);
+            assert!(matches!(
+                unpacker.unpack_udp_data(),
+                Err(FrameError::Malformed(cs::DATA))
+            ));
+
+            let mut unpacker = UdpDataUnpacker::new();
+            unpacker.append_data(vec![0, 0, 0, 0]);
+            assert!(unpacker.unpack_udp_data().is_err());
         }
     }
 }
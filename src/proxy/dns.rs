@@ -0,0 +1,136 @@
+use crate::client::*;
+use crate::dns::{Query, ResolveError};
+use async_std::io;
+use async_std::net::{TcpListener, TcpStream, UdpSocket};
+use async_std::prelude::*;
+use async_std::task;
+use futures::channel::mpsc::UnboundedSender;
+use futures::channel::oneshot;
+use std::str::from_utf8;
+use std::sync::Arc;
+use std::time::Duration;
+
+const MAX_QUERY_SIZE: usize = 4096;
+const TCP_IDLE_TIMEOUT_MS: u64 = 10000;
+
+// An address query to be resolved by the server through a tunnel port.
+pub struct DnsRequest {
+    query: Query,
+    reply: oneshot::Sender<Vec<u8>>,
+}
+
+impl DnsRequest {
+    pub async fn forward(self, mut read_port: TunnelReadPort, mut write_port: TunnelWritePort) {
+        write_port
+            .resolve(self.query.name().as_bytes().to_vec())
+            .await;
+
+        let result = match read_port.read().await {
+            TunnelPortMsg::ConnectOk(buf) => from_utf8(&buf)
+                .map(|ips| ips.split(',').filter_map(|ip| ip.parse().ok()).collect())
+                .map_err(|_| ResolveError::Failed),
+
+            TunnelPortMsg::ConnectErr(ConnectError::DnsFailure) => Err(ResolveError::NotFound),
+            TunnelPortMsg::ConnectErr(ConnectError::Timeout) => Err(ResolveError::Timeout),
+            _ => Err(ResolveError::Failed),
+        };
+
+        let _ = self.reply.send(self.query.answer(result));
+
+        read_port.drain();
+        write_port.close().await;
+    }
+}
+
+// Takes DNS queries over UDP and TCP and hands the address queries to `tx`,
+// so names are resolved by the server and never on the local network.
+pub async fn run_dns_forwarder(
+    socket: UdpSocket,
+    listener: TcpListener,
+    tx: UnboundedSender<DnsRequest>,
+) {
+    let u = run_udp_forwarder(Arc::new(socket), tx.clone());
+    let t = run_tcp_forwarder(listener, tx);
+    let _ = u.join(t).await;
+}
+
+async fn run_udp_forwarder(socket: Arc<UdpSocket>, tx: UnboundedSender<DnsRequest>) {
+    let mut buf = vec![0u8; MAX_QUERY_SIZE];
+
+    loop {
+        let (n, peer) = match socket.recv_from(&mut buf).await {
+            Ok(result) => result,
+            Err(_) => continue,
+        };
+
+        if let Some(reply) = submit(&buf[..n], &tx) {
+            let socket = socket.clone();
+            task::spawn(async move {
+                if let Ok(response) = reply.await {
+                    let _ = socket.send_to(&response, peer).await;
+                }
+            });
+        }
+    }
+}
+
+async fn run_tcp_forwarder(listener: TcpListener, tx: UnboundedSender<DnsRequest>) {
+    let mut incoming = listener.incoming();
+
+    while let Some(stream) = incoming.next().await {
+        if let Ok(stream) = stream {
+            let tx = tx.clone();
+            task::spawn(async move {
+                let _ = serve_tcp(stream, tx).await;
+            });
+        }
+    }
+}
+
+// Messages over TCP go with a 2 byte length in front, the queries of a
+// connection are answered one after another.
+async fn serve_tcp(mut stream: TcpStream, tx: UnboundedSender<DnsRequest>) -> io::Result<()> {
+    let timeout = Duration::from_millis(TCP_IDLE_TIMEOUT_MS);
+
+    loop {
+        let mut len = [0u8; 2];
+        io::timeout(timeout, stream.read_exact(&mut len)).await?;
+
+        let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf).await?;
+
+        let reply = match submit(&buf, &tx) {
+            Some(reply) => reply,
+            None => return Ok(()),
+        };
+
+        let response = match reply.await {
+            Ok(response) => response,
+            Err(_) => return Ok(()),
+        };
+
+        let mut buf = Vec::with_capacity(2 + response.len());
+        buf.extend_from_slice(&(response.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&response);
+        stream.write_all(&buf).await?;
+    }
+}
+
+// Returns where the response will come from, right away for queries that are
+// not forwarded. Malformed queries get no response.
+fn submit(buf: &[u8], tx: &UnboundedSender<DnsRequest>) -> Option<oneshot::Receiver<Vec<u8>>> {
+    let query = Query::parse(buf)?;
+    let (reply_tx, reply_rx) = oneshot::channel();
+
+    if query.wants_address() {
+        let request = DnsRequest {
+            query,
+            reply: reply_tx,
+        };
+        tx.unbounded_send(request).ok()?;
+    } else {
+        let _ = reply_tx.send(query.not_implemented());
+    }
+
+    Some(reply_rx)
+}
@@ -0,0 +1,257 @@
+use crate::client::*;
+use crate::protocol::{UdpDataPacker, UdpDataUnpacker};
+use crate::proxy::{Destination, Proxy};
+use async_std::io;
+use async_std::net::{TcpListener, TcpStream, UdpSocket};
+use async_std::prelude::*;
+use async_trait::async_trait;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+// A UDP session ends after this long without a datagram either way.
+const UDP_IDLE_TIMEOUT_MS: u64 = 60000;
+
+// Forwards what arrives on `local_addr` to a fixed destination on the server
+// side, for applications that speak no proxy protocol.
+#[derive(Clone)]
+pub struct LocalForward {
+    local_addr: SocketAddr,
+    host: String,
+    port: u16,
+    udp: bool,
+}
+
+impl LocalForward {
+    // Parses `local-address:remote-host:remote-port`, with `/udp` appended
+    // for UDP. The local address may be a bare port on 127.0.0.1, the remote
+    // host of UDP must be an IP address.
+    pub fn parse(s: &str) -> Option<LocalForward> {
+        let (s, udp) = match s.strip_suffix("/udp") {
+            Some(s) => (s, true),
+            None => (s.strip_suffix("/tcp").unwrap_or(s), false),
+        };
+
+        let (s, port) = s.rsplit_once(':')?;
+        let port = port.parse().ok()?;
+
+        let (local_addr, host) = s.match_indices(':').find_map(|(i, _)| {
+            let local_addr = s[..i].parse().ok().or_else(|| {
+                let port = s[..i].parse().ok()?;
+                Some(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port))
+            })?;
+
+            Some((local_addr, &s[i + 1..]))
+        })?;
+
+        let forward = LocalForward {
+            local_addr,
+            host: host.to_string(),
+            port,
+            udp,
+        };
+
+        if host.is_empty() || (udp && forward.udp_destination().is_none()) {
+            return None;
+        }
+
+        Some(forward)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn is_udp(&self) -> bool {
+        self.udp
+    }
+
+    fn udp_destination(&self) -> Option<SocketAddr> {
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        host.parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, self.port))
+    }
+}
+
+#[async_trait]
+impl Proxy for LocalForward {
+    async fn handshake(&mut self, _stream: &mut TcpStream) -> std::io::Result<Destination> {
+        Ok(Destination::DomainName(
+            self.host.as_bytes().to_vec(),
+            self.port,
+        ))
+    }
+
+    async fn destination_unreached(
+        &self,
+        _stream: &mut TcpStream,
+        _err: ConnectError,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    async fn destination_connected(
+        &self,
+        _stream: &mut TcpStream,
+        _bind_addr: SocketAddr,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// A connection or UDP session waiting for a tunnel port.
+pub enum ForwardRequest {
+    Tcp(TcpStream, LocalForward),
+    Udp(UdpSession),
+}
+
+impl ForwardRequest {
+    pub async fn run(self, read_port: TunnelReadPort, write_port: TunnelWritePort) {
+        match self {
+            ForwardRequest::Tcp(stream, mut forward) => {
+                forward
+                    .run_proxy_tunnel(stream, read_port, write_port)
+                    .await
+            }
+            ForwardRequest::Udp(session) => session.run(read_port, write_port).await,
+        }
+    }
+}
+
+pub async fn run_tcp_forward(
+    listener: TcpListener,
+    forward: LocalForward,
+    tx: UnboundedSender<ForwardRequest>,
+) {
+    let mut incoming = listener.incoming();
+
+    while let Some(stream) = incoming.next().await {
+        if let Ok(stream) = stream {
+            let request = ForwardRequest::Tcp(stream, forward.clone());
+            if tx.unbounded_send(request).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+// Every local peer gets a UDP_ASSOCIATE port of its own, so the replies find
+// their way back.
+pub async fn run_udp_forward(
+    socket: UdpSocket,
+    forward: LocalForward,
+    tx: UnboundedSender<ForwardRequest>,
+) {
+    let destination = match forward.udp_destination() {
+        Some(destination) => destination,
+        None => return,
+    };
+
+    let socket = Arc::new(socket);
+    let mut sessions: HashMap<SocketAddr, UnboundedSender<Vec<u8>>> = HashMap::new();
+    let mut buf = [0; 1500];
+
+    loop {
+        let (n, peer) = match socket.recv_from(&mut buf).await {
+            Ok(result) => result,
+            Err(_) => continue,
+        };
+
+        let data = match sessions.get(&peer) {
+            Some(session) => match session.unbounded_send(buf[..n].to_vec()) {
+                Ok(_) => continue,
+                Err(err) => err.into_inner(),
+            },
+            None => buf[..n].to_vec(),
+        };
+
+        sessions.retain(|_, session| !session.is_closed());
+
+        let (session_tx, rx) = unbounded();
+        let _ = session_tx.unbounded_send(data);
+        sessions.insert(peer, session_tx);
+
+        let session = UdpSession {
+            socket: socket.clone(),
+            peer,
+            destination,
+            rx,
+        };
+
+        if tx.unbounded_send(ForwardRequest::Udp(session)).is_err() {
+            break;
+        }
+    }
+}
+
+pub struct UdpSession {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    destination: SocketAddr,
+    rx: UnboundedReceiver<Vec<u8>>,
+}
+
+enum UdpEvent {
+    Local(Option<Vec<u8>>),
+    Remote(TunnelPortMsg),
+}
+
+impl UdpSession {
+    async fn run(mut self, mut read_port: TunnelReadPort, mut write_port: TunnelWritePort) {
+        let local_addr = self.socket.local_addr().map(|addr| addr.to_string());
+        write_port
+            .udp_associate(local_addr.unwrap_or_default().into_bytes())
+            .await;
+
+        if let TunnelPortMsg::ConnectOk(_) = read_port.read().await {
+            self.relay(&mut read_port, &mut write_port).await;
+        }
+
+        read_port.drain();
+        write_port.close().await;
+    }
+
+    async fn relay(&mut self, read_port: &mut TunnelReadPort, write_port: &mut TunnelWritePort) {
+        let udp_packer = UdpDataPacker;
+        let mut udp_unpacker = UdpDataUnpacker::new();
+        let timeout = Duration::from_millis(UDP_IDLE_TIMEOUT_MS);
+
+        loop {
+            let rx = &mut self.rx;
+            let local = async {
+                let data = io::timeout(timeout, async { Ok(rx.next().await) }).await;
+                UdpEvent::Local(data.ok().flatten())
+            };
+            let remote = async { UdpEvent::Remote(read_port.read().await) };
+
+            match local.race(remote).await {
+                UdpEvent::Local(Some(data)) => {
+                    let packed = udp_packer.pack_udp_data(&data, &self.destination);
+                    write_port.write(packed).await;
+                }
+
+                UdpEvent::Remote(TunnelPortMsg::Data(buf)) => {
+                    udp_unpacker.append_data(buf);
+
+                    loop {
+                        match udp_unpacker.unpack_udp_data() {
+                            Ok(Some((data, _))) => {
+                                let _ = self.socket.send_to(&data, self.peer).await;
+                            }
+                            Ok(None) => break,
+                            Err(err) => {
+                                error!("udp forward recv {}", err);
+                                return;
+                            }
+                        }
+                    }
+                }
+
+                _ => break,
+            }
+        }
+    }
+}
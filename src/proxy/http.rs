@@ -1,42 +1,134 @@
-use crate::proxy::{Destination, Proxy};
-use async_h1::server::{decode, Encoder};
-use async_std::io;
+use crate::client::*;
+use crate::proxy::{self, Destination, Proxy, ProxyUsers};
+use async_h1::server::Encoder;
+use async_std::channel::{self, Receiver, Sender};
+use async_std::io::{self, Read, Write};
 use async_std::net::TcpStream;
+use async_std::prelude::*;
 use async_trait::async_trait;
+use http_types::auth::BasicAuth;
+use http_types::headers::{CONNECTION, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION};
+use http_types::url::{Host, Url};
 use http_types::{Method, Response, StatusCode};
-use std::net::SocketAddr;
+use std::net::{Shutdown, SocketAddr};
+use std::str::from_utf8;
+use std::sync::Arc;
 
-pub struct Http;
+const MAX_HEAD_SIZE: usize = 65536;
+const MAX_HEADERS: usize = 128;
+const READ_SIZE: usize = 16384;
+
+// Headers that only concern one connection, never forwarded, along with the
+// ones named by Connection.
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "upgrade",
+];
+
+enum BodyLength {
+    None,
+    Fixed(u64),
+    Chunked,
+    // Only for responses, which may end with the connection.
+    UntilClose,
+}
+
+// A request of plain HTTP, its head rewritten to origin form.
+struct OriginRequest {
+    url: Url,
+    head: Vec<u8>,
+    body: BodyLength,
+    is_head: bool,
+}
+
+enum Head {
+    Connect(Url),
+    Plain(OriginRequest),
+}
+
+// What the client is owed next, in the order of its requests.
+enum Pending {
+    // The response to a forwarded request, which has no body for HEAD.
+    Response(bool),
+    // A request that is not forwarded, answered with the status once the
+    // responses before it are written, closing the connection.
+    Refuse(StatusCode),
+}
+
+// A response from the origin, its head without hop-by-hop headers.
+struct OriginResponse {
+    head: Vec<u8>,
+    body: BodyLength,
+    interim: bool,
+    keep_alive: bool,
+}
+
+// CONNECT, and plain HTTP requests with an absolute URI. The requests that
+// follow the first on a connection are forwarded as long as they go to the
+// same host, as the tunnel leads there. One for another host is answered with
+// 502 and the connection closed.
+pub struct Http {
+    users: Arc<ProxyUsers>,
+    user: Option<String>,
+    buffered: Vec<u8>,
+    request: Option<OriginRequest>,
+    pending_tx: Sender<Pending>,
+    pending_rx: Receiver<Pending>,
+}
 
 #[async_trait]
 impl Proxy for Http {
     async fn handshake(&mut self, stream: &mut TcpStream) -> std::io::Result<Destination> {
-        let http_req = decode(stream.clone()).await;
-
-        match http_req {
-            Ok(Some((request, _))) => {
-                let method = request.method();
-                let url = request.url();
-                if method == Method::Connect
-                    && url.host_str().is_some()
-                    && url.port_or_known_default().is_some()
-                {
-                    let host = url.host_str().unwrap().to_string();
-                    let port = url.port_or_known_default().unwrap();
-                    Ok(Destination::DomainName(host.into_bytes(), port))
-                } else {
-                    Ok(Destination::Unknown)
-                }
+        let mut reader = Reader::new(&mut *stream);
+        let head = match reader.read_head().await? {
+            Some(head) => parse_head(&head),
+            None => return Ok(Destination::Unknown),
+        };
+        self.buffered = reader.buf;
+
+        let (head, credentials) = match head {
+            Some(head) => head,
+            None => {
+                respond(stream, Response::new(StatusCode::BadRequest)).await?;
+                return Ok(Destination::Unknown);
+            }
+        };
+
+        if !self.users.is_empty() && !self.authenticate(credentials) {
+            let mut response = Response::new(StatusCode::ProxyAuthenticationRequired);
+            response.insert_header(PROXY_AUTHENTICATE, "Basic realm=\"stunnel\"");
+            respond(stream, response).await?;
+            return Ok(Destination::Unknown);
+        }
+
+        match head {
+            Head::Connect(url) => Ok(destination(&url)),
+
+            Head::Plain(request) => {
+                let destination = destination(&request.url);
+                self.request = Some(request);
+                Ok(destination)
             }
-            _ => Ok(Destination::Unknown),
         }
     }
 
-    async fn destination_unreached(&self, stream: &mut TcpStream) -> std::io::Result<()> {
-        let response = Response::new(StatusCode::NotFound);
-        let mut encoder = Encoder::new(response, Method::Connect);
-        io::copy(&mut encoder, stream).await?;
-        Ok(())
+    async fn destination_unreached(
+        &self,
+        stream: &mut TcpStream,
+        err: ConnectError,
+    ) -> std::io::Result<()> {
+        let status = match err {
+            ConnectError::Timeout => StatusCode::GatewayTimeout,
+            ConnectError::Denied => StatusCode::Forbidden,
+            _ => StatusCode::BadGateway,
+        };
+
+        respond(stream, Response::new(status)).await
     }
 
     async fn destination_connected(
@@ -44,9 +136,773 @@ impl Proxy for Http {
         stream: &mut TcpStream,
         _bind_addr: SocketAddr,
     ) -> std::io::Result<()> {
-        let response = Response::new(StatusCode::Ok);
-        let mut encoder = Encoder::new(response, Method::Connect);
-        io::copy(&mut encoder, stream).await?;
+        match self.request {
+            Some(_) => Ok(()),
+            None => respond(stream, Response::new(StatusCode::Ok)).await,
+        }
+    }
+
+    fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    async fn proxy_tunnel_read(&self, stream: &mut &TcpStream, mut write_port: TunnelWritePort) {
+        let request = match &self.request {
+            Some(request) => request,
+            None => {
+                if !self.buffered.is_empty() {
+                    write_port.write(self.buffered.clone()).await;
+                }
+                return proxy::proxy_tunnel_read(stream, write_port).await;
+            }
+        };
+
+        let mut reader = Reader::new(&mut *stream);
+        reader.buf = self.buffered.clone();
+
+        let result =
+            forward_requests(&mut reader, request, &mut write_port, &self.pending_tx).await;
+        match result {
+            Ok(Some(status)) => {
+                let _ = self.pending_tx.send(Pending::Refuse(status)).await;
+                write_port.drop().await;
+            }
+
+            Ok(None) => {
+                let _ = stream.shutdown(Shutdown::Read);
+                write_port.shutdown_write().await;
+                write_port.drop().await;
+            }
+
+            Err(_) => {
+                let _ = stream.shutdown(Shutdown::Both);
+                write_port.close().await;
+            }
+        }
+
+        self.pending_tx.close();
+    }
+
+    async fn proxy_tunnel_write(&self, stream: &mut &TcpStream, mut read_port: TunnelReadPort) {
+        if self.request.is_none() {
+            return proxy::proxy_tunnel_write(stream, read_port).await;
+        }
+
+        let mut reader = Reader::new(&mut read_port);
+        let _ = relay_responses(stream, &mut reader, &self.pending_rx).await;
+
+        let _ = stream.shutdown(Shutdown::Both);
+        read_port.drain();
+        read_port.close().await;
+    }
+}
+
+impl Http {
+    // Asks for Basic Proxy-Authorization when `users` has any, once for the
+    // first request of a connection.
+    pub fn new(users: Arc<ProxyUsers>) -> Self {
+        let (pending_tx, pending_rx) = channel::unbounded();
+
+        Self {
+            users,
+            user: None,
+            buffered: Vec::new(),
+            request: None,
+            pending_tx,
+            pending_rx,
+        }
+    }
+
+    fn authenticate(&mut self, credentials: Option<BasicAuth>) -> bool {
+        let auth = match credentials {
+            Some(auth) => auth,
+            None => return false,
+        };
+
+        if !self
+            .users
+            .check(auth.username(), auth.password().as_bytes())
+        {
+            error!("http proxy authentication of {} failed", auth.username());
+            return false;
+        }
+
+        self.user = Some(auth.username().to_string());
+        true
+    }
+}
+
+// Forwards requests until the client is done, returning the status to answer
+// the first one that cannot go to the host of the tunnel with.
+async fn forward_requests<S: Source>(
+    reader: &mut Reader<S>,
+    first: &OriginRequest,
+    write_port: &mut TunnelWritePort,
+    pending: &Sender<Pending>,
+) -> std::io::Result<Option<StatusCode>> {
+    forward_request(reader, first, write_port, pending).await?;
+
+    while let Some(head) = reader.read_head().await? {
+        match parse_head(&head) {
+            Some((Head::Plain(request), _)) if same_origin(&request.url, &first.url) => {
+                forward_request(reader, &request, write_port, pending).await?
+            }
+            Some(_) => return Ok(Some(StatusCode::BadGateway)),
+            None => return Ok(Some(StatusCode::BadRequest)),
+        }
+    }
+
+    Ok(None)
+}
+
+async fn forward_request<S: Source>(
+    reader: &mut Reader<S>,
+    request: &OriginRequest,
+    write_port: &mut TunnelWritePort,
+    pending: &Sender<Pending>,
+) -> std::io::Result<()> {
+    let _ = pending.send(Pending::Response(request.is_head)).await;
+    write_port.write(request.head.clone()).await;
+    forward_body(reader, &request.body, write_port).await
+}
+
+// Writes what the client is owed until the connection is to be closed.
+async fn relay_responses<S: Source>(
+    stream: &mut &TcpStream,
+    reader: &mut Reader<S>,
+    pending: &Receiver<Pending>,
+) -> std::io::Result<()> {
+    while let Ok(next) = pending.recv().await {
+        match next {
+            Pending::Response(is_head) => {
+                if !relay_response(stream, reader, is_head).await? {
+                    break;
+                }
+            }
+
+            Pending::Refuse(status) => {
+                let mut response = Response::new(status);
+                response.insert_header(CONNECTION, "close");
+                return respond(stream, response).await;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Writes a response along with the interim ones before it, returning whether
+// the connection stays open for the next.
+async fn relay_response<S: Source>(
+    stream: &mut &TcpStream,
+    reader: &mut Reader<S>,
+    is_head: bool,
+) -> std::io::Result<bool> {
+    loop {
+        let head = reader
+            .read_head()
+            .await?
+            .ok_or_else(|| std::io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let response = parse_response(&head, is_head)
+            .ok_or_else(|| std::io::Error::from(io::ErrorKind::InvalidData))?;
+
+        stream.write_all(&response.head).await?;
+        if !response.interim {
+            forward_body(reader, &response.body, stream).await?;
+            return Ok(response.keep_alive);
+        }
+    }
+}
+
+async fn forward_body<S: Source, K: Sink>(
+    reader: &mut Reader<S>,
+    body: &BodyLength,
+    sink: &mut K,
+) -> std::io::Result<()> {
+    match *body {
+        BodyLength::None => {}
+        BodyLength::Fixed(len) => forward_data(reader, len, sink).await?,
+        BodyLength::Chunked => loop {
+            let line = reader.read_line().await?;
+            let size = parse_chunk_size(&line)?;
+            sink.send(line).await?;
+
+            if size > 0 {
+                let len = size
+                    .checked_add(2)
+                    .ok_or_else(|| std::io::Error::from(io::ErrorKind::InvalidData))?;
+                forward_data(reader, len, sink).await?;
+                continue;
+            }
+
+            // The last chunk is followed by trailers up to an empty line.
+            loop {
+                let line = reader.read_line().await?;
+                let end = line == b"\r\n" || line == b"\n";
+                sink.send(line).await?;
+
+                if end {
+                    return Ok(());
+                }
+            }
+        },
+        BodyLength::UntilClose => loop {
+            let data = reader.read_some(READ_SIZE).await?;
+            if data.is_empty() {
+                return Ok(());
+            }
+            sink.send(data).await?;
+        },
+    }
+
+    Ok(())
+}
+
+async fn forward_data<S: Source, K: Sink>(
+    reader: &mut Reader<S>,
+    mut len: u64,
+    sink: &mut K,
+) -> std::io::Result<()> {
+    while len > 0 {
+        let data = reader.read_some(len.min(READ_SIZE as u64) as usize).await?;
+        if data.is_empty() {
+            return Err(std::io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        len -= data.len() as u64;
+        sink.send(data).await?;
+    }
+
+    Ok(())
+}
+
+// Where messages come from, the client connection or the tunnel.
+#[async_trait]
+trait Source: Send {
+    // Appends what arrives next to `buf`, nothing at the end.
+    async fn read_more(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize>;
+}
+
+#[async_trait]
+impl<R: Read + Unpin + Send> Source for &mut R {
+    async fn read_more(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        let mut data = vec![0u8; READ_SIZE];
+        let n = self.read(&mut data).await?;
+        buf.extend_from_slice(&data[..n]);
+        Ok(n)
+    }
+}
+
+#[async_trait]
+impl Source for &mut TunnelReadPort {
+    async fn read_more(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        loop {
+            match self.read().await {
+                TunnelPortMsg::Data(data) if data.is_empty() => continue,
+                TunnelPortMsg::Data(data) => {
+                    buf.extend_from_slice(&data);
+                    return Ok(data.len());
+                }
+                _ => return Ok(0),
+            }
+        }
+    }
+}
+
+// Where message bodies go, the tunnel or the client connection.
+#[async_trait]
+trait Sink: Send {
+    async fn send(&mut self, buf: Vec<u8>) -> std::io::Result<()>;
+}
+
+#[async_trait]
+impl Sink for TunnelWritePort {
+    async fn send(&mut self, buf: Vec<u8>) -> std::io::Result<()> {
+        self.write(buf).await;
         Ok(())
     }
 }
+
+#[async_trait]
+impl Sink for &TcpStream {
+    async fn send(&mut self, buf: Vec<u8>) -> std::io::Result<()> {
+        self.write_all(&buf).await
+    }
+}
+
+// Reads the messages of one side, keeping the bytes read past them.
+struct Reader<S> {
+    source: S,
+    buf: Vec<u8>,
+}
+
+impl<S: Source> Reader<S> {
+    fn new(source: S) -> Self {
+        Self {
+            source,
+            buf: Vec::new(),
+        }
+    }
+
+    async fn fill(&mut self) -> std::io::Result<usize> {
+        self.source.read_more(&mut self.buf).await
+    }
+
+    // Returns None when the other side closes the connection between
+    // messages.
+    async fn read_head(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        match self.read_until(b"\r\n\r\n").await {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && self.buf.is_empty() => {
+                Ok(None)
+            }
+            result => result.map(Some),
+        }
+    }
+
+    async fn read_line(&mut self) -> std::io::Result<Vec<u8>> {
+        self.read_until(b"\n").await
+    }
+
+    async fn read_until(&mut self, delimiter: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut start = 0;
+
+        loop {
+            if let Some(i) = self.buf[start..]
+                .windows(delimiter.len())
+                .position(|w| w == delimiter)
+            {
+                let rest = self.buf.split_off(start + i + delimiter.len());
+                return Ok(std::mem::replace(&mut self.buf, rest));
+            }
+
+            if self.buf.len() > MAX_HEAD_SIZE {
+                return Err(std::io::Error::from(io::ErrorKind::InvalidData));
+            }
+
+            start = self.buf.len().saturating_sub(delimiter.len() - 1);
+            if self.fill().await? == 0 {
+                return Err(std::io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+        }
+    }
+
+    // Takes up to `max` bytes, reading more only when none are left. Empty at
+    // the end.
+    async fn read_some(&mut self, max: usize) -> std::io::Result<Vec<u8>> {
+        if self.buf.is_empty() {
+            self.fill().await?;
+        }
+
+        let rest = self.buf.split_off(max.min(self.buf.len()));
+        Ok(std::mem::replace(&mut self.buf, rest))
+    }
+}
+
+// Returns the Basic credentials of Proxy-Authorization along with the head.
+fn parse_head(buf: &[u8]) -> Option<(Head, Option<BasicAuth>)> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    if !request.parse(buf).ok()?.is_complete() {
+        return None;
+    }
+
+    let method = request.method?;
+    let target = request.path?;
+    let credentials = request
+        .headers
+        .iter()
+        .find(|header| {
+            header
+                .name
+                .eq_ignore_ascii_case(PROXY_AUTHORIZATION.as_str())
+        })
+        .and_then(|header| basic_credentials(header.value));
+
+    if method.eq_ignore_ascii_case(Method::Connect.as_ref()) {
+        let url = Url::parse(&format!("http://{}/", target)).ok()?;
+        return Some((Head::Connect(url), credentials));
+    }
+
+    // Only absolute URIs of http name the host to forward to.
+    let url = Url::parse(target).ok()?;
+    if url.scheme() != "http" || url.host_str().is_none() {
+        return None;
+    }
+
+    let body = body_length(request.headers)?;
+    let head = origin_head(method, target, request.version?, &url, request.headers);
+    let is_head = method.eq_ignore_ascii_case(Method::Head.as_ref());
+    let request = OriginRequest {
+        url,
+        head,
+        body,
+        is_head,
+    };
+    Some((Head::Plain(request), credentials))
+}
+
+// Works out how the body of a response is framed and whether the origin keeps
+// the connection open after it. The client connection follows the origin, told
+// so by Connection: close.
+fn parse_response(buf: &[u8], is_head: bool) -> Option<OriginResponse> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    if !response.parse(buf).ok()?.is_complete() {
+        return None;
+    }
+
+    let version = response.version?;
+    let code = response.code?;
+    let connection = connection_options(response.headers);
+
+    // 101 is not interim here, Upgrade is never forwarded.
+    let interim = (100..200).contains(&code) && code != 101;
+    let body = if interim || is_head || code == 204 || code == 304 {
+        BodyLength::None
+    } else {
+        match body_length(response.headers) {
+            Some(BodyLength::None) | None => BodyLength::UntilClose,
+            Some(body) => body,
+        }
+    };
+
+    let keep_alive = match body {
+        BodyLength::UntilClose => false,
+        _ if version == 0 => connection.iter().any(|option| option == "keep-alive"),
+        _ => !connection.iter().any(|option| option == "close"),
+    };
+
+    let mut head = format!(
+        "HTTP/1.{} {} {}\r\n",
+        version,
+        code,
+        response.reason.unwrap_or_default()
+    )
+    .into_bytes();
+    append_headers(&mut head, response.headers, &connection, &[]);
+
+    if !interim && !keep_alive {
+        head.extend_from_slice(b"Connection: close\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+
+    Some(OriginResponse {
+        head,
+        body,
+        interim,
+        keep_alive,
+    })
+}
+
+fn basic_credentials(value: &[u8]) -> Option<BasicAuth> {
+    let (scheme, credentials) = from_utf8(value).ok()?.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    BasicAuth::from_credentials(credentials.trim()).ok()
+}
+
+// Rewrites the request line to origin form and the Host header to the host of
+// the URI, dropping hop-by-hop headers.
+fn origin_head(
+    method: &str,
+    target: &str,
+    version: u8,
+    url: &Url,
+    headers: &[httparse::Header],
+) -> Vec<u8> {
+    let after_scheme = &target[target.find("//").map_or(0, |i| i + 2)..];
+    let path = match after_scheme.find(['/', '?']) {
+        Some(i) if after_scheme[i..].starts_with('?') => format!("/{}", &after_scheme[i..]),
+        Some(i) => after_scheme[i..].to_string(),
+        None => String::from("/"),
+    };
+
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+
+    let mut head = format!(
+        "{} {} HTTP/1.{}\r\nHost: {}\r\n",
+        method, path, version, host
+    )
+    .into_bytes();
+
+    append_headers(&mut head, headers, &connection_options(headers), &["host"]);
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+// The options of Connection, lowercase.
+fn connection_options(headers: &[httparse::Header]) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("connection"))
+        .flat_map(|header| {
+            String::from_utf8_lossy(header.value)
+                .split(',')
+                .map(|name| name.trim().to_ascii_lowercase())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+// Appends the end-to-end headers, leaving out the ones in `skip`.
+fn append_headers(
+    head: &mut Vec<u8>,
+    headers: &[httparse::Header],
+    connection: &[String],
+    skip: &[&str],
+) {
+    for header in headers {
+        let name = header.name.to_ascii_lowercase();
+        if skip.contains(&name.as_str())
+            || HOP_BY_HOP_HEADERS.contains(&name.as_str())
+            || connection.contains(&name)
+        {
+            continue;
+        }
+
+        head.extend_from_slice(header.name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header.value);
+        head.extend_from_slice(b"\r\n");
+    }
+}
+
+// None for a body that cannot be framed, including one with both lengths,
+// repeated Transfer-Encoding or Content-Length values that differ, which are
+// open to request smuggling.
+fn body_length(headers: &[httparse::Header]) -> Option<BodyLength> {
+    let values = |name: &str| -> Option<Vec<&str>> {
+        headers
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| from_utf8(header.value).ok().map(str::trim))
+            .collect()
+    };
+
+    match (
+        &values("transfer-encoding")?[..],
+        &values("content-length")?[..],
+    ) {
+        ([], []) => Some(BodyLength::None),
+
+        ([coding], []) => {
+            let last = coding.rsplit(',').next()?.trim();
+            if last.eq_ignore_ascii_case("chunked") {
+                Some(BodyLength::Chunked)
+            } else {
+                None
+            }
+        }
+
+        ([], [len, others @ ..]) if others.iter().all(|other| other == len) => {
+            len.parse().ok().map(BodyLength::Fixed)
+        }
+
+        _ => None,
+    }
+}
+
+fn parse_chunk_size(line: &[u8]) -> std::io::Result<u64> {
+    from_utf8(line)
+        .ok()
+        .and_then(|line| line.split(';').next())
+        .and_then(|size| u64::from_str_radix(size.trim(), 16).ok())
+        .ok_or_else(|| std::io::Error::from(io::ErrorKind::InvalidData))
+}
+
+fn same_origin(url: &Url, other: &Url) -> bool {
+    url.host_str() == other.host_str()
+        && url.port_or_known_default() == other.port_or_known_default()
+}
+
+fn destination(url: &Url) -> Destination {
+    match (url.host(), url.port_or_known_default()) {
+        (Some(Host::Domain(host)), Some(port)) => {
+            Destination::DomainName(host.as_bytes().to_vec(), port)
+        }
+        (Some(Host::Ipv4(ip)), Some(port)) => {
+            Destination::Address(SocketAddr::new(ip.into(), port))
+        }
+        (Some(Host::Ipv6(ip)), Some(port)) => {
+            Destination::Address(SocketAddr::new(ip.into(), port))
+        }
+        _ => Destination::Unknown,
+    }
+}
+
+async fn respond<W: Write + Unpin + Send>(
+    stream: &mut W,
+    response: Response,
+) -> std::io::Result<()> {
+    let mut encoder = Encoder::new(response, Method::Connect);
+    io::copy(&mut encoder, stream).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(name: &'a str, value: &'a str) -> httparse::Header<'a> {
+        httparse::Header {
+            name,
+            value: value.as_bytes(),
+        }
+    }
+
+    #[test]
+    fn body_length_of_single_headers() {
+        assert!(matches!(body_length(&[]), Some(BodyLength::None)));
+        assert!(matches!(
+            body_length(&[header("Content-Length", " 42 ")]),
+            Some(BodyLength::Fixed(42))
+        ));
+        assert!(matches!(
+            body_length(&[header("Transfer-Encoding", "gzip, chunked")]),
+            Some(BodyLength::Chunked)
+        ));
+        assert!(body_length(&[header("Transfer-Encoding", "chunked, gzip")]).is_none());
+        assert!(body_length(&[header("Content-Length", "-1")]).is_none());
+    }
+
+    #[test]
+    fn body_length_rejects_ambiguous_framing() {
+        let both = [
+            header("Transfer-Encoding", "chunked"),
+            header("Content-Length", "5"),
+        ];
+        assert!(body_length(&both).is_none());
+
+        let lengths = [
+            header("Content-Length", "0"),
+            header("Content-Length", "50"),
+        ];
+        assert!(body_length(&lengths).is_none());
+
+        let codings = [
+            header("Transfer-Encoding", "chunked"),
+            header("transfer-encoding", "gzip"),
+        ];
+        assert!(body_length(&codings).is_none());
+
+        let same = [header("Content-Length", "7"), header("content-length", "7")];
+        assert!(matches!(body_length(&same), Some(BodyLength::Fixed(7))));
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        assert_eq!(parse_chunk_size(b"1a\r\n").unwrap(), 26);
+        assert_eq!(parse_chunk_size(b"0;name=value\r\n").unwrap(), 0);
+        assert_eq!(parse_chunk_size(b"ffffffffffffffff\r\n").unwrap(), u64::MAX);
+        assert!(parse_chunk_size(b"10000000000000000\r\n").is_err());
+        assert!(parse_chunk_size(b"zz\r\n").is_err());
+        assert!(parse_chunk_size(b"\r\n").is_err());
+    }
+
+    #[test]
+    fn origin_head_rewrites_target_and_drops_hop_by_hop_headers() {
+        let target = "http://example.com:8080/a/b?c=d";
+        let url = Url::parse(target).unwrap();
+        let headers = [
+            header("Host", "other"),
+            header("Proxy-Connection", "keep-alive"),
+            header("Proxy-Authorization", "Basic YTpi"),
+            header("Connection", "X-Private"),
+            header("X-Private", "1"),
+            header("Accept", "*/*"),
+        ];
+
+        let head = origin_head("GET", target, 1, &url, &headers);
+        assert_eq!(
+            head,
+            b"GET /a/b?c=d HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*\r\n\r\n"
+        );
+
+        let target = "http://example.com?q";
+        let url = Url::parse(target).unwrap();
+        let head = origin_head("HEAD", target, 0, &url, &[]);
+        assert_eq!(head, b"HEAD /?q HTTP/1.0\r\nHost: example.com\r\n\r\n");
+    }
+
+    #[test]
+    fn response_keeps_connection_with_length() {
+        let response = parse_response(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: keep-alive, X-Hop\r\n\
+              Keep-Alive: timeout=5\r\nX-Hop: 1\r\nX-End: 1\r\n\r\n",
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            response.head,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nX-End: 1\r\n\r\n"
+        );
+        assert!(matches!(response.body, BodyLength::Fixed(3)));
+        assert!(!response.interim);
+        assert!(response.keep_alive);
+    }
+
+    #[test]
+    fn response_closes_connection() {
+        let response =
+            parse_response(b"HTTP/1.0 200 OK\r\nContent-Length: 3\r\n\r\n", false).unwrap();
+        assert_eq!(
+            response.head,
+            b"HTTP/1.0 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\n"
+        );
+        assert!(!response.keep_alive);
+
+        let response = parse_response(b"HTTP/1.1 200 OK\r\n\r\n", false).unwrap();
+        assert!(matches!(response.body, BodyLength::UntilClose));
+        assert!(!response.keep_alive);
+
+        let response = parse_response(
+            b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+            false,
+        )
+        .unwrap();
+        assert!(!response.keep_alive);
+    }
+
+    #[test]
+    fn response_bodies() {
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let response = parse_response(chunked, false).unwrap();
+        assert!(matches!(response.body, BodyLength::Chunked));
+        assert!(response.keep_alive);
+
+        let response =
+            parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\n", true).unwrap();
+        assert!(matches!(response.body, BodyLength::None));
+        assert!(response.keep_alive);
+
+        for status in [&b"204 No Content"[..], b"304 Not Modified"] {
+            let head = [&b"HTTP/1.1 "[..], status, b"\r\n\r\n"].concat();
+            let response = parse_response(&head, false).unwrap();
+            assert!(matches!(response.body, BodyLength::None));
+            assert!(response.keep_alive);
+        }
+    }
+
+    #[test]
+    fn interim_response() {
+        let response = parse_response(b"HTTP/1.1 100 Continue\r\n\r\n", false).unwrap();
+        assert_eq!(response.head, b"HTTP/1.1 100 Continue\r\n\r\n");
+        assert!(response.interim);
+        assert!(matches!(response.body, BodyLength::None));
+
+        let response = parse_response(b"HTTP/1.1 101 Switching Protocols\r\n\r\n", false).unwrap();
+        assert!(!response.interim);
+    }
+
+    #[test]
+    fn malformed_response() {
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n", false).is_none());
+        assert!(parse_response(b"garbage\r\n\r\n", false).is_none());
+    }
+}
@@ -2,29 +2,100 @@ use crate::client::*;
 use async_std::net::TcpStream;
 use async_std::prelude::*;
 use async_trait::async_trait;
+use crypto::util::fixed_time_eq;
+use std::collections::HashMap;
+use std::fmt;
 use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
 use std::str::from_utf8;
 
+pub mod dns;
+pub mod forward;
 pub mod http;
+pub mod reverse;
+pub mod socks4;
 pub mod socks5;
 
 pub enum Destination {
     Address(SocketAddr),
     DomainName(Vec<u8>, u16),
     UdpAssociate(SocketAddr),
+    // The `host:port` of the peer expected to connect.
+    Bind(String),
     Unknown,
 }
 
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Address(addr) => write!(f, "{}", addr),
+            Destination::DomainName(domain_name, port) => {
+                write!(f, "{}:{}", String::from_utf8_lossy(domain_name), port)
+            }
+            Destination::UdpAssociate(addr) => write!(f, "udp associate {}", addr),
+            Destination::Bind(addr) => write!(f, "bind for {}", addr),
+            Destination::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+// Usernames and passwords the proxies accept, no authentication is asked for
+// while there are none.
+pub struct ProxyUsers {
+    users: HashMap<String, String>,
+}
+
+impl ProxyUsers {
+    pub fn new() -> Self {
+        Self {
+            users: HashMap::new(),
+        }
+    }
+
+    pub fn add_user(&mut self, name: String, password: String) {
+        self.users.insert(name, password);
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn check(&self, name: &str, password: &[u8]) -> bool {
+        self.users
+            .get(name)
+            .is_some_and(|expected| fixed_time_eq(expected.as_bytes(), password))
+    }
+}
+
+impl Default for ProxyUsers {
+    fn default() -> Self {
+        ProxyUsers::new()
+    }
+}
+
 #[async_trait]
 pub trait Proxy: Sync {
     async fn handshake(&mut self, stream: &mut TcpStream) -> std::io::Result<Destination>;
-    async fn destination_unreached(&self, stream: &mut TcpStream) -> std::io::Result<()>;
+    async fn destination_unreached(
+        &self,
+        stream: &mut TcpStream,
+        err: ConnectError,
+    ) -> std::io::Result<()>;
     async fn destination_connected(
         &self,
         stream: &mut TcpStream,
         bind_addr: SocketAddr,
     ) -> std::io::Result<()>;
 
+    // Who the client authenticated as in the handshake, for logging and
+    // per-user policy.
+    fn user(&self) -> Option<&str> {
+        None
+    }
+
     async fn proxy_tunnel_read(&self, stream: &mut &TcpStream, write_port: TunnelWritePort) {
         proxy_tunnel_read(stream, write_port).await;
     }
@@ -39,7 +110,12 @@ pub trait Proxy: Sync {
         mut read_port: TunnelReadPort,
         mut write_port: TunnelWritePort,
     ) {
-        match self.handshake(&mut stream).await {
+        let destination = self.handshake(&mut stream).await;
+        if let (Ok(destination), Some(user)) = (&destination, self.user()) {
+            info!("user {} requests {}", user, destination);
+        }
+
+        match destination {
             Ok(Destination::Address(addr)) => {
                 let mut buf = Vec::new();
                 let _ = std::io::Write::write_fmt(&mut buf, format_args!("{}", addr));
@@ -56,22 +132,29 @@ pub trait Proxy: Sync {
                 write_port.udp_associate(buf).await;
             }
 
+            Ok(Destination::Bind(addr)) => {
+                write_port.bind(addr.into_bytes()).await;
+            }
+
             _ => {
                 return write_port.close().await;
             }
         }
 
         let addr = match read_port.read().await {
-            TunnelPortMsg::ConnectOk(buf) => {
-                from_utf8(&buf).unwrap().to_socket_addrs().unwrap().nth(0)
-            }
-
-            _ => None,
+            TunnelPortMsg::ConnectOk(buf) => from_utf8(&buf)
+                .ok()
+                .and_then(|addr| addr.to_socket_addrs().ok())
+                .and_then(|mut addrs| addrs.next())
+                .ok_or(ConnectError::General),
+
+            TunnelPortMsg::ConnectErr(err) => Err(err),
+            _ => Err(ConnectError::General),
         };
 
         let success = match addr {
-            Some(addr) => self.destination_connected(&mut stream, addr).await.is_ok(),
-            None => self.destination_unreached(&mut stream).await.is_ok() && false,
+            Ok(addr) => self.destination_connected(&mut stream, addr).await.is_ok(),
+            Err(err) => self.destination_unreached(&mut stream, err).await.is_ok() && false,
         };
 
         if success {
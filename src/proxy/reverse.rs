@@ -0,0 +1,94 @@
+use super::{proxy_tunnel_read, proxy_tunnel_write};
+use crate::client::*;
+use crate::protocol::CONNECT_TIMEOUT_MS;
+use async_std::io;
+use async_std::net::{SocketAddr, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use std::str::from_utf8;
+use std::time::Duration;
+
+// How long a forward waits before it listens again after losing the listener.
+const RELISTEN_INTERVAL_MS: u64 = 5000;
+
+// Has the server listen on `remote_addr` and forwards its connections to
+// `local_addr` on the client side.
+#[derive(Clone)]
+pub struct RemoteForward {
+    remote_addr: String,
+    local_addr: String,
+}
+
+impl RemoteForward {
+    // Parses `remote-address:local-address`, e.g. `0.0.0.0:2222:127.0.0.1:22`,
+    // the local host may be a domain name.
+    pub fn parse(s: &str) -> Option<RemoteForward> {
+        s.match_indices(':').find_map(|(i, _)| {
+            let (remote_addr, local_addr) = (&s[..i], &s[i + 1..]);
+            let (host, port) = local_addr.rsplit_once(':')?;
+
+            if remote_addr.parse::<SocketAddr>().is_err()
+                || host.is_empty()
+                || port.parse::<u16>().is_err()
+            {
+                return None;
+            }
+
+            Some(RemoteForward {
+                remote_addr: remote_addr.to_string(),
+                local_addr: local_addr.to_string(),
+            })
+        })
+    }
+
+    // Returns a while after the listener is gone, to be run again on another
+    // port.
+    pub async fn run(&self, mut read_port: TunnelReadPort, mut write_port: TunnelWritePort) {
+        write_port
+            .listen(self.remote_addr.as_bytes().to_vec())
+            .await;
+
+        match read_port.read().await {
+            TunnelPortMsg::ConnectOk(buf) => {
+                let addr = from_utf8(&buf).unwrap_or_default();
+                info!("remote forward {} to {}", addr, self.local_addr);
+
+                while let TunnelPortMsg::Accept(write, read) = read_port.read().await {
+                    let local_addr = self.local_addr.clone();
+                    task::spawn(async move {
+                        connect_local(&local_addr, read, write).await;
+                    });
+                }
+
+                info!("remote forward {} lost", addr);
+            }
+
+            TunnelPortMsg::ConnectErr(err) => {
+                error!("remote forward listen {} error: {}", self.remote_addr, err);
+            }
+
+            _ => {}
+        }
+
+        read_port.drain();
+        write_port.close().await;
+        task::sleep(Duration::from_millis(RELISTEN_INTERVAL_MS)).await;
+    }
+}
+
+async fn connect_local(addr: &str, mut read_port: TunnelReadPort, mut write_port: TunnelWritePort) {
+    let timeout = Duration::from_millis(CONNECT_TIMEOUT_MS);
+    let stream = match io::timeout(timeout, TcpStream::connect(addr)).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            error!("remote forward connect {} error: {}", addr, err);
+            read_port.drain();
+            return write_port.close().await;
+        }
+    };
+
+    let (reader, writer) = &mut (&stream, &stream);
+    let r = proxy_tunnel_read(reader, write_port);
+    let w = proxy_tunnel_write(writer, read_port);
+    let _ = r.join(w).await;
+}
@@ -0,0 +1,121 @@
+use crate::client::*;
+use crate::proxy::{Destination, Proxy, ProxyUsers};
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use async_trait::async_trait;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+
+pub const VER: u8 = 4;
+const REPLY_VER: u8 = 0;
+
+const CMD_CONNECT: u8 = 1;
+
+const REP_GRANTED: u8 = 90;
+const REP_REJECTED: u8 = 91;
+
+// Longest user id or SOCKS4a domain name taken from a request.
+const MAX_FIELD_LEN: usize = 255;
+
+// SOCKS4 CONNECT, with the destination given as an IPv4 address or, in
+// SOCKS4a, as a domain name resolved by the server.
+pub struct Socks4 {
+    users: Arc<ProxyUsers>,
+}
+
+#[async_trait]
+impl Proxy for Socks4 {
+    async fn handshake(&mut self, stream: &mut TcpStream) -> std::io::Result<Destination> {
+        self.handshake_socks4(stream).await
+    }
+
+    async fn destination_unreached(
+        &self,
+        stream: &mut TcpStream,
+        _err: ConnectError,
+    ) -> std::io::Result<()> {
+        destination_result(stream, REP_REJECTED).await
+    }
+
+    async fn destination_connected(
+        &self,
+        stream: &mut TcpStream,
+        _bind_addr: SocketAddr,
+    ) -> std::io::Result<()> {
+        destination_result(stream, REP_GRANTED).await
+    }
+}
+
+impl Socks4 {
+    // SOCKS4 carries no password, so every request is rejected while `users`
+    // has any.
+    pub fn new(users: Arc<ProxyUsers>) -> Self {
+        Self { users }
+    }
+
+    async fn handshake_socks4(&mut self, stream: &mut TcpStream) -> std::io::Result<Destination> {
+        let mut buf = [0u8; 8];
+        stream.read_exact(&mut buf).await?;
+
+        let port = u16::from_be_bytes([buf[2], buf[3]]);
+        let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+        let _user_id = read_field(stream).await?;
+
+        // 0.0.0.x with x other than 0 announces a SOCKS4a domain name.
+        let octets = ip.octets();
+        let domain_name = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+            Some(read_field(stream).await?)
+        } else {
+            None
+        };
+
+        if buf[0] != VER || buf[1] != CMD_CONNECT {
+            destination_result(stream, REP_REJECTED).await?;
+            return Ok(Destination::Unknown);
+        }
+
+        if !self.users.is_empty() {
+            error!("socks4 request rejected, authentication required");
+            destination_result(stream, REP_REJECTED).await?;
+            return Ok(Destination::Unknown);
+        }
+
+        match domain_name {
+            Some(domain_name) if !domain_name.is_empty() => {
+                Ok(Destination::DomainName(domain_name, port))
+            }
+            Some(_) => {
+                destination_result(stream, REP_REJECTED).await?;
+                Ok(Destination::Unknown)
+            }
+            None => Ok(Destination::Address(SocketAddr::V4(SocketAddrV4::new(
+                ip, port,
+            )))),
+        }
+    }
+}
+
+// Reads a NUL terminated field, failing on one longer than MAX_FIELD_LEN.
+async fn read_field(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut field = Vec::new();
+    let mut byte = [0u8; 1];
+
+    loop {
+        stream.read_exact(&mut byte).await?;
+        if byte[0] == 0 {
+            return Ok(field);
+        }
+
+        if field.len() == MAX_FIELD_LEN {
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+        field.push(byte[0]);
+    }
+}
+
+// The reply carries no address worth reporting, clients ignore it for
+// CONNECT.
+async fn destination_result(stream: &mut TcpStream, rep: u8) -> std::io::Result<()> {
+    let buf = [REPLY_VER, rep, 0, 0, 0, 0, 0, 0];
+    stream.write_all(&buf).await
+}
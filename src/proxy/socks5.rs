@@ -1,30 +1,43 @@
 use crate::client::*;
 use crate::protocol::{UdpDataPacker, UdpDataUnpacker};
-use crate::proxy::{self, Destination, Proxy};
+use crate::proxy::{self, Destination, Proxy, ProxyUsers};
 use async_std::channel::{self, Receiver, Sender};
-use async_std::io;
+use async_std::io::{self, Write};
 use async_std::net::{TcpStream, UdpSocket};
 use async_std::prelude::*;
 use async_trait::async_trait;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::str::from_utf8;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
-const VER: u8 = 5;
+pub const VER: u8 = 5;
 const RSV: u8 = 0;
 
 const CMD_CONNECT: u8 = 1;
+const CMD_BIND: u8 = 2;
 const CMD_UDP_ASSOCIATE: u8 = 3;
 
 const METHOD_NO_AUTH: u8 = 0;
+const METHOD_USERNAME_PASSWORD: u8 = 2;
 const METHOD_NO_ACCEPT: u8 = 0xFF;
 
+// RFC 1929 username/password subnegotiation.
+const AUTH_VER: u8 = 1;
+const AUTH_SUCCESS: u8 = 0;
+const AUTH_FAILURE: u8 = 1;
+
 const ATYP_IPV4: u8 = 1;
 const ATYP_DOMAINNAME: u8 = 3;
 const ATYP_IPV6: u8 = 4;
 
 const REP_SUCCESS: u8 = 0;
 const REP_FAILURE: u8 = 1;
+const REP_NOT_ALLOWED: u8 = 2;
+const REP_HOST_UNREACHABLE: u8 = 4;
+const REP_CONNECTION_REFUSED: u8 = 5;
+const REP_TTL_EXPIRED: u8 = 6;
 
 struct UdpContext {
     socket: UdpSocket,
@@ -36,6 +49,9 @@ struct UdpContext {
 pub struct Socks5 {
     udp_socks5: AtomicBool,
     udp: Option<UdpContext>,
+    bind: bool,
+    users: Arc<ProxyUsers>,
+    user: Option<String>,
 }
 
 #[async_trait]
@@ -44,8 +60,12 @@ impl Proxy for Socks5 {
         self.handshake_socks5(stream).await
     }
 
-    async fn destination_unreached(&self, stream: &mut TcpStream) -> std::io::Result<()> {
-        destination_unreached(stream).await
+    async fn destination_unreached(
+        &self,
+        stream: &mut TcpStream,
+        err: ConnectError,
+    ) -> std::io::Result<()> {
+        destination_unreached(stream, err).await
     }
 
     async fn destination_connected(
@@ -56,6 +76,10 @@ impl Proxy for Socks5 {
         destination_connected(stream, bind_addr).await
     }
 
+    fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
     async fn proxy_tunnel_read(&self, stream: &mut &TcpStream, write_port: TunnelWritePort) {
         if self.udp_socks5.load(Ordering::Relaxed) {
             self.udp_proxy_tunnel_read(write_port).await;
@@ -69,6 +93,8 @@ impl Proxy for Socks5 {
             let h = self.udp_proxy_connection_holder(stream);
             let w = self.udp_proxy_tunnel_write(read_port);
             h.join(w).await;
+        } else if self.bind {
+            self.bind_proxy_tunnel_write(stream, read_port).await;
         } else {
             proxy::proxy_tunnel_write(stream, read_port).await;
         }
@@ -76,10 +102,14 @@ impl Proxy for Socks5 {
 }
 
 impl Socks5 {
-    pub fn new() -> Self {
+    // Asks for a username and password when `users` has any.
+    pub fn new(users: Arc<ProxyUsers>) -> Self {
         Self {
             udp_socks5: AtomicBool::new(false),
             udp: None,
+            bind: false,
+            users,
+            user: None,
         }
     }
 
@@ -95,18 +125,28 @@ impl Socks5 {
         let mut methods = vec![0; buf[1] as usize];
         stream.read_exact(&mut methods).await?;
 
-        if !methods.into_iter().any(|method| method == METHOD_NO_AUTH) {
+        let method = if self.users.is_empty() {
+            METHOD_NO_AUTH
+        } else {
+            METHOD_USERNAME_PASSWORD
+        };
+
+        if !methods.into_iter().any(|m| m == method) {
             choose_method(stream, METHOD_NO_ACCEPT).await?;
             return Ok(Destination::Unknown);
         }
 
-        choose_method(stream, METHOD_NO_AUTH).await?;
+        choose_method(stream, method).await?;
+
+        if method == METHOD_USERNAME_PASSWORD && !self.authenticate(stream).await? {
+            return Ok(Destination::Unknown);
+        }
 
         let mut buf = [0u8; 4];
         stream.read_exact(&mut buf).await?;
 
         let cmd = buf[1];
-        if cmd != CMD_CONNECT && cmd != CMD_UDP_ASSOCIATE {
+        if cmd != CMD_CONNECT && cmd != CMD_BIND && cmd != CMD_UDP_ASSOCIATE {
             return Ok(Destination::Unknown);
         }
 
@@ -115,14 +155,7 @@ impl Socks5 {
                 let mut ipv4_addr = [0u8; 6];
                 stream.read_exact(&mut ipv4_addr).await?;
 
-                let ipv4 = unsafe { *(ipv4_addr.as_ptr() as *const u32) };
-                let port = unsafe { *(ipv4_addr.as_ptr().offset(4) as *const u16) };
-                let addr = SocketAddr::V4(SocketAddrV4::new(
-                    Ipv4Addr::from(u32::from_be(ipv4)),
-                    u16::from_be(port),
-                ));
-
-                Destination::Address(addr)
+                Destination::Address(parse_ipv4_addr(&ipv4_addr))
             }
 
             ATYP_DOMAINNAME => {
@@ -133,12 +166,18 @@ impl Socks5 {
                 let mut buf = vec![0u8; len + 2];
                 stream.read_exact(&mut buf).await?;
 
-                let port = unsafe { *(buf.as_ptr().offset(len as isize) as *const u16) };
+                let port = u16::from_be_bytes([buf[len], buf[len + 1]]);
                 buf.truncate(len);
-                Destination::DomainName(buf, u16::from_be(port))
+                Destination::DomainName(buf, port)
+            }
+
+            ATYP_IPV6 => {
+                let mut ipv6_addr = [0u8; 18];
+                stream.read_exact(&mut ipv6_addr).await?;
+
+                Destination::Address(parse_ipv6_addr(&ipv6_addr))
             }
 
-            ATYP_IPV6 => Destination::Unknown,
             _ => Destination::Unknown,
         };
 
@@ -146,12 +185,55 @@ impl Socks5 {
             return Ok(destination);
         }
 
-        return self.handshake_udp_associate(destination).await;
+        if cmd == CMD_BIND {
+            if let Destination::Unknown = destination {
+                return Ok(destination);
+            }
+
+            self.bind = true;
+            return Ok(Destination::Bind(destination.to_string()));
+        }
+
+        let local_ip = stream.local_addr()?.ip();
+        return self.handshake_udp_associate(destination, local_ip).await;
+    }
+
+    async fn authenticate(&mut self, stream: &mut TcpStream) -> std::io::Result<bool> {
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await?;
+
+        if buf[0] != AUTH_VER {
+            stream.write_all(&[AUTH_VER, AUTH_FAILURE]).await?;
+            return Ok(false);
+        }
+
+        let mut name = vec![0u8; buf[1] as usize];
+        stream.read_exact(&mut name).await?;
+
+        let mut len = [0u8; 1];
+        stream.read_exact(&mut len).await?;
+
+        let mut password = vec![0u8; len[0] as usize];
+        stream.read_exact(&mut password).await?;
+
+        let name = String::from_utf8_lossy(&name).into_owned();
+        if !self.users.check(&name, &password) {
+            error!("socks5 authentication of {} failed", name);
+            stream.write_all(&[AUTH_VER, AUTH_FAILURE]).await?;
+            return Ok(false);
+        }
+
+        stream.write_all(&[AUTH_VER, AUTH_SUCCESS]).await?;
+        self.user = Some(name);
+        Ok(true)
     }
 
+    // The relay socket listens on the address the client reached us on, so
+    // it is of the same family.
     async fn handshake_udp_associate(
         &mut self,
         destination: Destination,
+        local_ip: IpAddr,
     ) -> std::io::Result<Destination> {
         match destination {
             Destination::Address(_) => {}
@@ -160,7 +242,7 @@ impl Socks5 {
             }
         };
 
-        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0)).await?;
         let alive = AtomicBool::new(true);
         let local_addr = socket.local_addr()?;
         let (tx, rx) = channel::bounded(1);
@@ -227,15 +309,22 @@ impl Socks5 {
                 break;
             }
 
-            let udp_data = match read_port.read().await {
-                TunnelPortMsg::Data(buf) => {
-                    udp_unpacker.append_data(buf);
-                    udp_unpacker.unpack_udp_data()
-                }
+            match read_port.read().await {
+                TunnelPortMsg::Data(buf) => udp_unpacker.append_data(buf),
                 _ => break,
-            };
+            }
+
+            loop {
+                let (data, source) = match udp_unpacker.unpack_udp_data() {
+                    Ok(Some(udp_data)) => udp_data,
+                    Ok(None) => break,
+                    Err(err) => {
+                        error!("socks5 udp associate recv {}", err);
+                        read_port.drain();
+                        return;
+                    }
+                };
 
-            if let Some((data, source)) = udp_data {
                 if let Some(buf) = pack_socks5_udp_request(&data, &source) {
                     let _ = udp.socket.send_to(&buf, client_addr).await;
                 }
@@ -245,6 +334,39 @@ impl Socks5 {
         read_port.drain();
     }
 
+    // The first reply of BIND went out when the server listened, the second
+    // goes out when the peer connects.
+    async fn bind_proxy_tunnel_write(
+        &self,
+        stream: &mut &TcpStream,
+        mut read_port: TunnelReadPort,
+    ) {
+        let replied = match read_port.read().await {
+            TunnelPortMsg::ConnectOk(buf) => {
+                let peer = from_utf8(&buf)
+                    .ok()
+                    .and_then(|addr| addr.parse().ok())
+                    .unwrap_or_else(|| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)));
+                destination_result(stream, peer, REP_SUCCESS).await.is_ok()
+            }
+
+            TunnelPortMsg::ConnectErr(err) => {
+                let _ = destination_unreached(stream, err).await;
+                false
+            }
+
+            _ => false,
+        };
+
+        if replied {
+            proxy::proxy_tunnel_write(stream, read_port).await;
+        } else {
+            let _ = stream.shutdown(Shutdown::Both);
+            read_port.drain();
+            read_port.close().await;
+        }
+    }
+
     async fn udp_proxy_connection_holder(&self, stream: &mut &TcpStream) {
         let mut buf = [0; 1024];
 
@@ -267,45 +389,68 @@ impl Socks5 {
 fn pack_socks5_udp_request(data: &[u8], addr: &SocketAddr) -> Option<Vec<u8>> {
     match addr {
         SocketAddr::V4(ipv4) => {
-            let mut buf = vec![0u8; 10 + data.len()];
-            buf[3] = ATYP_IPV4;
-            unsafe {
-                *(buf.as_ptr().offset(4) as *mut u32) = u32::from(ipv4.ip().clone()).to_be();
-                *(buf.as_ptr().offset(8) as *mut u16) = ipv4.port().to_be();
-            }
-
-            buf[10..].copy_from_slice(data);
+            let mut buf = Vec::with_capacity(10 + data.len());
+            buf.extend_from_slice(&[RSV, RSV, 0, ATYP_IPV4]);
+            buf.extend_from_slice(&ipv4.ip().octets());
+            buf.extend_from_slice(&ipv4.port().to_be_bytes());
+            buf.extend_from_slice(data);
             return Some(buf);
         }
 
-        _ => return None,
+        SocketAddr::V6(ipv6) => {
+            let mut buf = Vec::with_capacity(22 + data.len());
+            buf.extend_from_slice(&[RSV, RSV, 0, ATYP_IPV6]);
+            buf.extend_from_slice(&ipv6.ip().octets());
+            buf.extend_from_slice(&ipv6.port().to_be_bytes());
+            buf.extend_from_slice(data);
+            return Some(buf);
+        }
     }
 }
 
 fn unpack_socks5_udp_request(buf: &[u8]) -> Option<(Vec<u8>, SocketAddr)> {
-    if buf.len() < 10 {
+    if buf.len() < 4 {
         return None;
     }
 
-    if buf[3] != ATYP_IPV4 {
-        return None;
-    }
+    let (addr, data) = match buf[3] {
+        ATYP_IPV4 if buf.len() >= 10 => (parse_ipv4_addr(&buf[4..10]), &buf[10..]),
+        ATYP_IPV6 if buf.len() >= 22 => (parse_ipv6_addr(&buf[4..22]), &buf[22..]),
+        _ => return None,
+    };
 
-    let ipv4_addr = &buf[4..10];
-    let ipv4 = unsafe { *(ipv4_addr.as_ptr() as *const u32) };
-    let port = unsafe { *(ipv4_addr.as_ptr().offset(4) as *const u16) };
-    let addr = SocketAddr::V4(SocketAddrV4::new(
-        Ipv4Addr::from(u32::from_be(ipv4)),
-        u16::from_be(port),
-    ));
+    return Some((data.to_vec(), addr));
+}
+
+// Parses the 4 address bytes and 2 port bytes of ATYP_IPV4.
+fn parse_ipv4_addr(buf: &[u8]) -> SocketAddr {
+    let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
+    let port = u16::from_be_bytes([buf[4], buf[5]]);
+    SocketAddr::V4(SocketAddrV4::new(ip, port))
+}
 
-    let data = &buf[10..];
-    return Some((data.iter().cloned().collect(), addr));
+// Parses the 16 address bytes and 2 port bytes of ATYP_IPV6.
+fn parse_ipv6_addr(buf: &[u8]) -> SocketAddr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&buf[..16]);
+    let port = u16::from_be_bytes([buf[16], buf[17]]);
+    SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
 }
 
-async fn destination_unreached(stream: &mut TcpStream) -> std::io::Result<()> {
+async fn destination_unreached<W: Write + Unpin + Send>(
+    stream: &mut W,
+    err: ConnectError,
+) -> std::io::Result<()> {
+    let rep = match err {
+        ConnectError::General => REP_FAILURE,
+        ConnectError::Refused => REP_CONNECTION_REFUSED,
+        ConnectError::Unreachable | ConnectError::DnsFailure => REP_HOST_UNREACHABLE,
+        ConnectError::Timeout => REP_TTL_EXPIRED,
+        ConnectError::Denied => REP_NOT_ALLOWED,
+    };
+
     let bind_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
-    destination_result(stream, bind_addr, REP_FAILURE).await
+    destination_result(stream, bind_addr, rep).await
 }
 
 async fn destination_connected(
@@ -320,8 +465,8 @@ async fn choose_method(stream: &mut TcpStream, method: u8) -> std::io::Result<()
     stream.write_all(&buf).await
 }
 
-async fn destination_result(
-    stream: &mut TcpStream,
+async fn destination_result<W: Write + Unpin + Send>(
+    stream: &mut W,
     bind_addr: SocketAddr,
     rsp: u8,
 ) -> std::io::Result<()> {
@@ -333,10 +478,8 @@ async fn destination_result(
             buf[1] = rsp;
             buf[2] = RSV;
             buf[3] = ATYP_IPV4;
-            unsafe {
-                *(buf.as_ptr().offset(4) as *mut u32) = u32::from(ipv4.ip().clone()).to_be();
-                *(buf.as_ptr().offset(8) as *mut u16) = ipv4.port().to_be();
-            }
+            buf[4..8].copy_from_slice(&ipv4.ip().octets());
+            buf[8..10].copy_from_slice(&ipv4.port().to_be_bytes());
 
             stream.write_all(&buf).await?
         }
@@ -348,10 +491,8 @@ async fn destination_result(
             buf[1] = rsp;
             buf[2] = RSV;
             buf[3] = ATYP_IPV6;
-            unsafe {
-                *(buf.as_ptr().offset(4) as *mut u128) = u128::from(ipv6.ip().clone()).to_be();
-                *(buf.as_ptr().offset(20) as *mut u16) = ipv6.port().to_be();
-            }
+            buf[4..20].copy_from_slice(&ipv6.ip().octets());
+            buf[20..22].copy_from_slice(&ipv6.port().to_be_bytes());
 
             stream.write_all(&buf).await?
         }
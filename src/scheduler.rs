@@ -0,0 +1,114 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::vec::Vec;
+
+use async_std::stream::Stream;
+
+// Largest DATA payload written in one go, a bulk transfer on one port delays
+// the other ports by at most one chunk.
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024;
+
+pub enum Priority {
+    // Written before any queued data.
+    Control,
+    // Chunked and queued on the port.
+    Data(u32),
+    // Written after the data already queued on the port, like a close.
+    Ordered(u32),
+}
+
+pub trait Schedule: Sized {
+    fn priority(&self) -> Priority;
+
+    // Splits a data message into messages of at most `size` bytes each.
+    fn split(self, size: usize) -> Vec<Self>;
+}
+
+// Reorders the messages of a tunnel writer. Control messages go first, data
+// goes round-robin between the ports one chunk at a time.
+pub struct Scheduler<S: Stream> {
+    stream: S,
+    ended: bool,
+    control: VecDeque<S::Item>,
+    ports: HashMap<u32, VecDeque<S::Item>>,
+    round_robin: VecDeque<u32>,
+}
+
+impl<S: Stream> Scheduler<S>
+where
+    S::Item: Schedule,
+{
+    pub fn new(stream: S) -> Scheduler<S> {
+        Scheduler {
+            stream,
+            ended: false,
+            control: VecDeque::new(),
+            ports: HashMap::new(),
+            round_robin: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, msg: S::Item) {
+        match msg.priority() {
+            Priority::Control => self.control.push_back(msg),
+
+            Priority::Ordered(id) => match self.ports.get_mut(&id) {
+                Some(queue) => queue.push_back(msg),
+                None => self.control.push_back(msg),
+            },
+
+            Priority::Data(id) => {
+                if !self.ports.contains_key(&id) {
+                    self.round_robin.push_back(id);
+                }
+
+                let queue = self.ports.entry(id).or_default();
+                queue.extend(msg.split(MAX_CHUNK_SIZE));
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<S::Item> {
+        if let Some(msg) = self.control.pop_front() {
+            return Some(msg);
+        }
+
+        let id = self.round_robin.pop_front()?;
+        let queue = self.ports.get_mut(&id)?;
+        let msg = queue.pop_front();
+
+        if queue.is_empty() {
+            self.ports.remove(&id);
+        } else {
+            self.round_robin.push_back(id);
+        }
+
+        msg
+    }
+}
+
+impl<S: Stream + Unpin> Stream for Scheduler<S>
+where
+    S::Item: Schedule + Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Take in everything ready, so a control message never waits behind
+        // data that arrived earlier.
+        while !self.ended {
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Ready(Some(msg)) => self.push(msg),
+                Poll::Ready(None) => self.ended = true,
+                Poll::Pending => break,
+            }
+        }
+
+        match self.pop() {
+            Some(msg) => Poll::Ready(Some(msg)),
+            None if self.ended => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
@@ -1,20 +1,25 @@
 use std::collections::HashMap;
-use std::net::Shutdown;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
 use std::str::from_utf8;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 use async_std::io::{self, Read, Write};
-use async_std::net::{TcpStream, UdpSocket};
+use async_std::net::{TcpListener, TcpStream, UdpSocket};
 use async_std::prelude::*;
 use async_std::task;
 
-use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::channel::mpsc::{unbounded, Sender, UnboundedReceiver, UnboundedSender};
 use futures::sink::SinkExt;
 
+use super::compression::CompressionMetrics;
 use super::cryptor::*;
+use super::dns::{ResolveError, Resolver};
+use super::handshake::*;
 use super::protocol::*;
+use super::scheduler::{Priority, Schedule, Scheduler};
 use super::timer;
 use super::ucp::UcpStream;
 use super::util::*;
@@ -27,17 +32,44 @@ enum TunnelMsg {
     CSShutdownWrite(u32),
     CSConnectDN(u32, Vec<u8>, u16),
     CSData(u8, u32, Vec<u8>),
+    CSWindowUpdate(u32, u32),
+    CSRekey,
 
+    SCOpenPort(u32, TcpStream),
     SCClosePort(u32),
     SCShutdownWrite(u32),
     SCConnectOk(u32, Vec<u8>),
+    SCConnectErr(u32, ConnectError),
     SCData(u32, Vec<u8>),
+    SCWindowUpdate(u32, u32),
 
     TunnelPortHalfDrop(u32),
     Heartbeat,
     CloseTunnel,
 }
 
+impl Schedule for TunnelMsg {
+    fn priority(&self) -> Priority {
+        match *self {
+            TunnelMsg::SCData(id, _) => Priority::Data(id),
+            TunnelMsg::SCShutdownWrite(id)
+            | TunnelMsg::SCClosePort(id)
+            | TunnelMsg::TunnelPortHalfDrop(id) => Priority::Ordered(id),
+            _ => Priority::Control,
+        }
+    }
+
+    fn split(self, size: usize) -> Vec<TunnelMsg> {
+        match self {
+            TunnelMsg::SCData(id, buf) if buf.len() > size => buf
+                .chunks(size)
+                .map(|chunk| TunnelMsg::SCData(id, chunk.to_vec()))
+                .collect(),
+            msg => vec![msg],
+        }
+    }
+}
+
 enum TunnelPortMsg {
     ConnectDN(Vec<u8>, u16),
     Data(u8, Vec<u8>),
@@ -45,50 +77,119 @@ enum TunnelPortMsg {
     ClosePort,
 }
 
+pub struct TunnelConfig {
+    pub users: UserTable,
+    pub suite: CipherSuite,
+    pub replay_cache: ReplayCache,
+    // Padding budget in percent of the data sent.
+    pub padding: Option<u32>,
+    pub compress: bool,
+    pub compression_metrics: Arc<CompressionMetrics>,
+    pub resolver: Arc<Resolver>,
+    // Whether clients may have the server listen for them.
+    pub remote_forward: bool,
+}
+
+impl TunnelConfig {
+    fn capabilities(&self) -> u32 {
+        if self.compress {
+            capability::SUPPORTED | capability::COMPRESSION
+        } else {
+            capability::SUPPORTED
+        }
+    }
+
+    // Where to count DATA when the session compresses it.
+    fn compression(&self, session: &Session) -> Option<&CompressionMetrics> {
+        if session.supports(capability::COMPRESSION) {
+            Some(&self.compression_metrics)
+        } else {
+            None
+        }
+    }
+}
+
 pub struct TcpTunnel;
 pub struct UcpTunnel;
 
 struct TunnelWritePort {
     id: u32,
     tx: Sender<TunnelMsg>,
+    credit: u64,
+    credit_rx: UnboundedReceiver<u64>,
 }
 
 struct TunnelReadPort {
     id: u32,
     tx: Sender<TunnelMsg>,
-    rx: Option<Receiver<TunnelPortMsg>>,
+    rx: Option<UnboundedReceiver<TunnelPortMsg>>,
+    consumed: u32,
 }
 
 struct Port {
     count: u32,
-    tx: Sender<TunnelPortMsg>,
+    tx: UnboundedSender<TunnelPortMsg>,
+    credit_tx: UnboundedSender<u64>,
+    // Bytes the client may still send before it needs a window update.
+    window: u64,
 }
 
-struct PortHub(HashMap<u32, Port>);
+// The second field tells whether flow control was negotiated.
+struct PortHub(HashMap<u32, Port>, bool);
 
 impl TcpTunnel {
-    pub fn new(key: Vec<u8>, stream: TcpStream) {
+    pub fn new(config: Arc<TunnelConfig>, stream: TcpStream) {
         task::spawn(async move {
-            tcp_tunnel_core_task(key, stream).await;
+            tcp_tunnel_core_task(config, stream).await;
         });
     }
 }
 
 impl UcpTunnel {
-    pub fn new(key: Vec<u8>, stream: UcpStream) {
+    pub fn new(config: Arc<TunnelConfig>, stream: UcpStream) {
         task::spawn(async move {
-            ucp_tunnel_core_task(key, stream).await;
+            ucp_tunnel_core_task(config, stream).await;
         });
     }
 }
 
 impl TunnelWritePort {
+    // Has the core open a port to the client for a connection accepted on
+    // this listening port.
+    async fn open_remote_port(&mut self, stream: TcpStream) {
+        let _ = self.tx.send(TunnelMsg::SCOpenPort(self.id, stream)).await;
+    }
+
     async fn connect_ok(&mut self, buf: Vec<u8>) {
         let _ = self.tx.send(TunnelMsg::SCConnectOk(self.id, buf)).await;
     }
 
-    async fn write(&mut self, buf: Vec<u8>) {
-        let _ = self.tx.send(TunnelMsg::SCData(self.id, buf)).await;
+    async fn connect_err(&mut self, err: ConnectError) {
+        let _ = self.tx.send(TunnelMsg::SCConnectErr(self.id, err)).await;
+    }
+
+    // Sends as much as the credit allows and waits for the client to grant
+    // more for the rest, so a slow consumer on the other side stalls only
+    // this port, and a buffer larger than the window still goes out.
+    async fn write(&mut self, mut buf: Vec<u8>) {
+        loop {
+            if self.credit == 0 && !buf.is_empty() {
+                match self.credit_rx.next().await {
+                    Some(credit) => self.credit = self.credit.saturating_add(credit),
+                    None => return,
+                }
+                continue;
+            }
+
+            let rest = buf.split_off(self.credit.min(buf.len() as u64) as usize);
+            self.credit -= buf.len() as u64;
+            let _ = self.tx.send(TunnelMsg::SCData(self.id, buf)).await;
+
+            if rest.is_empty() {
+                return;
+            }
+            buf = rest;
+        }
     }
 
     async fn shutdown_write(&mut self) {
@@ -110,14 +211,25 @@ impl TunnelReadPort {
     }
 
     async fn read(&mut self) -> TunnelPortMsg {
-        match self.rx {
+        let msg = match self.rx {
             Some(ref mut receiver) => match receiver.next().await {
                 Some(msg) => msg,
                 None => TunnelPortMsg::ClosePort,
             },
 
             None => TunnelPortMsg::ClosePort,
+        };
+
+        if let TunnelPortMsg::Data(cs::DATA, ref buf) = msg {
+            self.consumed += buf.len() as u32;
+            if self.consumed >= WINDOW_UPDATE_THRESHOLD {
+                let update = TunnelMsg::SCWindowUpdate(self.id, self.consumed);
+                let _ = self.tx.send(update).await;
+                self.consumed = 0;
+            }
         }
+
+        msg
     }
 
     async fn close(&mut self) {
@@ -130,12 +242,50 @@ impl TunnelReadPort {
 }
 
 impl PortHub {
-    fn new() -> Self {
-        PortHub(HashMap::new())
+    fn new(flow_control: bool) -> Self {
+        PortHub(HashMap::new(), flow_control)
     }
 
-    fn add_port(&mut self, id: u32, tx: Sender<TunnelPortMsg>) {
-        self.0.insert(id, Port { count: 2, tx: tx });
+    fn add_port(
+        &mut self,
+        id: u32,
+        tx: UnboundedSender<TunnelPortMsg>,
+        credit_tx: UnboundedSender<u64>,
+    ) {
+        // Without flow control the port may send as much as it likes.
+        let credit = if self.1 {
+            INITIAL_WINDOW as u64
+        } else {
+            u64::MAX
+        };
+        let _ = credit_tx.unbounded_send(credit);
+
+        self.0.insert(
+            id,
+            Port {
+                count: 2,
+                tx,
+                credit_tx,
+                window: INITIAL_WINDOW as u64,
+            },
+        );
+    }
+
+    fn add_credit(&mut self, id: u32, credit: u32) {
+        if let Some(value) = self.0.get(&id) {
+            let _ = value.credit_tx.unbounded_send(credit as u64);
+        }
+    }
+
+    // Returns false when the port is gone and no update should be sent.
+    fn grant_window(&mut self, id: u32, credit: u32) -> bool {
+        match self.0.get_mut(&id) {
+            Some(value) if self.1 => {
+                value.window += credit as u64;
+                true
+            }
+            _ => false,
+        }
     }
 
     fn drop_port_half(&mut self, id: u32) {
@@ -164,8 +314,22 @@ impl PortHub {
             .await;
     }
 
-    async fn client_send_data(&mut self, id: u32, op: u8, buf: Vec<u8>) {
+    // Returns false when the client sent more than the port's window.
+    async fn client_send_data(&mut self, id: u32, op: u8, buf: Vec<u8>) -> bool {
+        let flow_control = self.1;
+
+        if let Some(value) = self.0.get_mut(&id) {
+            if flow_control && op == cs::DATA {
+                if buf.len() as u64 > value.window {
+                    return false;
+                }
+
+                value.window -= buf.len() as u64;
+            }
+        }
+
         self.try_send_msg(id, TunnelPortMsg::Data(op, buf)).await;
+        true
     }
 
     async fn client_shutdown(&mut self, id: u32) {
@@ -174,7 +338,7 @@ impl PortHub {
 
     async fn try_send_msg(&mut self, id: u32, msg: TunnelPortMsg) {
         if let Some(value) = self.0.get_mut(&id) {
-            if value.tx.send(msg).await.is_err() {
+            if value.tx.unbounded_send(msg).is_err() {
                 self.0.remove(&id);
             }
         }
@@ -235,13 +399,75 @@ async fn tunnel_port_read_tcp(stream: &mut &TcpStream, mut read_port: TunnelRead
     }
 }
 
-async fn tunnel_port_task(mut read_port: TunnelReadPort, write_port: TunnelWritePort) {
+async fn tunnel_port_task(
+    resolver: Arc<Resolver>,
+    remote_forward: bool,
+    mut read_port: TunnelReadPort,
+    write_port: TunnelWritePort,
+) {
     let msg = read_port.read().await;
     match msg {
         TunnelPortMsg::Data(cs::UDP_ASSOCIATE, _) => {
             tunnel_port_task_udp(msg, read_port, write_port).await
         }
-        _ => tunnel_port_task_tcp(msg, read_port, write_port).await,
+        TunnelPortMsg::Data(cs::RESOLVE, buf) => {
+            read_port.drain();
+            tunnel_port_task_resolve(&resolver, buf, write_port).await
+        }
+        TunnelPortMsg::Data(cs::LISTEN, buf) => {
+            tunnel_port_task_listen(remote_forward, buf, read_port, write_port).await
+        }
+        TunnelPortMsg::Data(cs::BIND, buf) => {
+            tunnel_port_task_bind(&resolver, buf, read_port, write_port).await
+        }
+        _ => tunnel_port_task_tcp(&resolver, msg, read_port, write_port).await,
+    }
+}
+
+// Relays the datagrams of a port with one socket per address family, so a
+// port reaches IPv4 and IPv6 destinations alike.
+struct UdpRelay {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+}
+
+impl UdpRelay {
+    async fn bind() -> Option<UdpRelay> {
+        let v4 = UdpSocket::bind("0.0.0.0:0").await.ok();
+        let v6 = UdpSocket::bind("[::]:0").await.ok();
+
+        if v4.is_none() && v6.is_none() {
+            return None;
+        }
+
+        Some(UdpRelay { v4, v6 })
+    }
+
+    async fn send_to(&self, data: &[u8], addr: SocketAddr) {
+        let socket = if addr.is_ipv4() { &self.v4 } else { &self.v6 };
+
+        if let Some(socket) = socket {
+            let _ = socket.send_to(data, addr).await;
+        }
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        let (v4, v6) = match (&self.v4, &self.v6) {
+            (Some(v4), Some(v6)) => (v4, v6),
+            (Some(socket), None) | (None, Some(socket)) => return socket.recv_from(buf).await,
+            (None, None) => unreachable!(),
+        };
+
+        let mut buf6 = vec![0u8; buf.len()];
+        let (result, from_v6) = async { (v4.recv_from(buf).await, false) }
+            .race(async { (v6.recv_from(&mut buf6).await, true) })
+            .await;
+
+        if let (Ok((n, _)), true) = (&result, from_v6) {
+            buf[..*n].copy_from_slice(&buf6[..*n]);
+        }
+
+        result
     }
 }
 
@@ -250,9 +476,9 @@ async fn tunnel_port_task_udp(
     read_port: TunnelReadPort,
     mut write_port: TunnelWritePort,
 ) {
-    let socket = match UdpSocket::bind("0.0.0.0:0").await {
-        Ok(s) => s,
-        Err(_) => return write_port.close().await,
+    let socket = match UdpRelay::bind().await {
+        Some(s) => s,
+        None => return write_port.close().await,
     };
 
     match msg {
@@ -270,7 +496,7 @@ async fn tunnel_port_task_udp(
 
 async fn tunnel_port_write_udp(
     running: &AtomicBool,
-    socket: &UdpSocket,
+    socket: &UdpRelay,
     mut write_port: TunnelWritePort,
 ) {
     let udp_packer = UdpDataPacker;
@@ -295,7 +521,7 @@ async fn tunnel_port_write_udp(
 
 async fn tunnel_port_read_udp(
     running: &AtomicBool,
-    socket: &UdpSocket,
+    socket: &UdpRelay,
     mut read_port: TunnelReadPort,
 ) {
     let mut udp_unpacker = UdpDataUnpacker::new();
@@ -303,8 +529,19 @@ async fn tunnel_port_read_udp(
         match read_port.read().await {
             TunnelPortMsg::Data(cs::DATA, buf) => {
                 udp_unpacker.append_data(buf);
-                if let Some((data, addr)) = udp_unpacker.unpack_udp_data() {
-                    let _ = socket.send_to(&data, addr).await;
+
+                loop {
+                    match udp_unpacker.unpack_udp_data() {
+                        Ok(Some((data, addr))) => socket.send_to(&data, addr).await,
+                        Ok(None) => break,
+                        Err(err) => {
+                            error!("udp associate recv {}", err);
+                            read_port.drain();
+                            read_port.close().await;
+                            running.store(false, Ordering::Relaxed);
+                            return;
+                        }
+                    }
                 }
             }
 
@@ -318,28 +555,260 @@ async fn tunnel_port_read_udp(
     }
 }
 
-async fn tunnel_port_task_tcp(
-    msg: TunnelPortMsg,
-    read_port: TunnelReadPort,
+// Answers a RESOLVE with the addresses of the name separated by commas, for
+// the DNS forwarder of the client.
+async fn tunnel_port_task_resolve(
+    resolver: &Resolver,
+    domain_name: Vec<u8>,
+    mut write_port: TunnelWritePort,
+) {
+    let result = match from_utf8(&domain_name) {
+        Ok(domain_name) => resolver.resolve(domain_name).await,
+        Err(_) => Err(ResolveError::Failed),
+    };
+
+    match result {
+        Ok(ips) => {
+            let ips: Vec<String> = ips.iter().map(|ip| ip.to_string()).collect();
+            write_port.connect_ok(ips.join(",").into_bytes()).await;
+        }
+
+        Err(ResolveError::NotFound) => write_port.connect_err(ConnectError::DnsFailure).await,
+        Err(ResolveError::Timeout) => write_port.connect_err(ConnectError::Timeout).await,
+        Err(ResolveError::Failed) => write_port.connect_err(ConnectError::General).await,
+    }
+
+    write_port.close().await;
+}
+
+// Listens on the address of a LISTEN as long as the port is open, and has the
+// core open a port to the client for every connection accepted.
+async fn tunnel_port_task_listen(
+    remote_forward: bool,
+    address: Vec<u8>,
+    mut read_port: TunnelReadPort,
     mut write_port: TunnelWritePort,
 ) {
-    let stream = match msg {
-        TunnelPortMsg::Data(cs::CONNECT, buf) => {
-            TcpStream::connect(from_utf8(&buf).unwrap()).await.ok()
+    let listener = match listen_tcp(remote_forward, &address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            read_port.drain();
+            write_port.connect_err(err).await;
+            return write_port.close().await;
+        }
+    };
+
+    match listener.local_addr() {
+        Ok(addr) => {
+            info!("Remote forward listening on {}", addr);
+            write_port.connect_ok(addr.to_string().into_bytes()).await;
         }
 
+        Err(_) => {
+            read_port.drain();
+            return write_port.close().await;
+        }
+    }
+
+    loop {
+        let accepted = async { Some(listener.accept().await) }
+            .race(async {
+                read_port.read().await;
+                None
+            })
+            .await;
+
+        match accepted {
+            Some(Ok((stream, _))) => write_port.open_remote_port(stream).await,
+            Some(Err(err)) => error!("Remote forward accept error: {}", err),
+            None => break,
+        }
+    }
+
+    if let Ok(addr) = listener.local_addr() {
+        info!("Remote forward on {} closed", addr);
+    }
+
+    read_port.drain();
+    write_port.close().await;
+}
+
+async fn listen_tcp(remote_forward: bool, address: &[u8]) -> Result<TcpListener, ConnectError> {
+    if !remote_forward {
+        return Err(ConnectError::Denied);
+    }
+
+    let addr: SocketAddr = match from_utf8(address).map(|addr| addr.parse()) {
+        Ok(Ok(addr)) => addr,
+        _ => return Err(ConnectError::General),
+    };
+
+    TcpListener::bind(addr)
+        .await
+        .map_err(|err| ConnectError::from_io_error(&err))
+}
+
+// Listens for the one connection of a BIND, answering ConnectOk with the
+// address it listens on and once more with the peer that connects, which is
+// then relayed like a CONNECT. Only the host the address names may connect,
+// others are turned away, and no connection within BIND_TIMEOUT_MS fails it.
+async fn tunnel_port_task_bind(
+    resolver: &Resolver,
+    address: Vec<u8>,
+    mut read_port: TunnelReadPort,
+    mut write_port: TunnelWritePort,
+) {
+    let (listener, peer_ip) = match bind_tcp(resolver, &address).await {
+        Ok(bound) => bound,
+        Err(err) => {
+            read_port.drain();
+            write_port.connect_err(err).await;
+            return write_port.close().await;
+        }
+    };
+
+    match listener.local_addr() {
+        Ok(addr) => write_port.connect_ok(addr.to_string().into_bytes()).await,
+        Err(_) => {
+            read_port.drain();
+            return write_port.close().await;
+        }
+    }
+
+    let accept = async {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            if peer_ip.is_unspecified() || peer.ip().to_canonical() == peer_ip.to_canonical() {
+                return Ok((stream, peer));
+            }
+            error!("bind for {} refused connection from {}", peer_ip, peer);
+        }
+    };
+
+    let timeout = Duration::from_millis(BIND_TIMEOUT_MS);
+    let accepted = async { Some(io::timeout(timeout, accept).await) }
+        .race(async {
+            read_port.read().await;
+            None
+        })
+        .await;
+    drop(listener);
+
+    match accepted {
+        Some(Ok((stream, peer))) => {
+            write_port.connect_ok(peer.to_string().into_bytes()).await;
+            tunnel_port_relay_tcp(stream, read_port, write_port).await;
+        }
+
+        Some(Err(err)) => {
+            read_port.drain();
+            write_port
+                .connect_err(ConnectError::from_io_error(&err))
+                .await;
+            write_port.close().await;
+        }
+
+        None => {
+            read_port.drain();
+            write_port.close().await;
+        }
+    }
+}
+
+// Listens on the local address the server reaches the peer of a BIND from, so
+// the peer can connect to it, along with the address of the peer.
+async fn bind_tcp(
+    resolver: &Resolver,
+    address: &[u8],
+) -> Result<(TcpListener, IpAddr), ConnectError> {
+    let (host, port) = match from_utf8(address).map(|addr| addr.rsplit_once(':')) {
+        Ok(Some((host, port))) => (host.trim_start_matches('[').trim_end_matches(']'), port),
+        _ => return Err(ConnectError::General),
+    };
+    let port: u16 = port.parse().map_err(|_| ConnectError::General)?;
+
+    let ip = match host.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => match resolver.resolve(host).await {
+            Ok(ips) => *ips.first().ok_or(ConnectError::DnsFailure)?,
+            Err(ResolveError::Timeout) => return Err(ConnectError::Timeout),
+            Err(_) => return Err(ConnectError::DnsFailure),
+        },
+    };
+
+    let any: IpAddr = if ip.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+
+    let local_ip = local_ip_to(any, SocketAddr::new(ip, port))
+        .await
+        .unwrap_or(any);
+
+    TcpListener::bind(SocketAddr::new(local_ip, 0))
+        .await
+        .map(|listener| (listener, ip))
+        .map_err(|err| ConnectError::from_io_error(&err))
+}
+
+// Connecting a UDP socket sends nothing, it only picks the route.
+async fn local_ip_to(any: IpAddr, addr: SocketAddr) -> Option<IpAddr> {
+    if addr.ip().is_unspecified() {
+        return None;
+    }
+
+    let socket = UdpSocket::bind(SocketAddr::new(any, 0)).await.ok()?;
+    socket.connect(addr).await.ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+// Resolves names apart from connecting, so a DNS failure can be told from an
+// unreachable host.
+async fn connect_tcp(resolver: &Resolver, msg: TunnelPortMsg) -> Result<TcpStream, ConnectError> {
+    let addrs: Vec<SocketAddr> = match msg {
+        TunnelPortMsg::Data(cs::CONNECT, buf) => match from_utf8(&buf).map(|a| a.parse()) {
+            Ok(Ok(addr)) => vec![addr],
+            _ => return Err(ConnectError::General),
+        },
+
         TunnelPortMsg::ConnectDN(domain_name, port) => {
-            TcpStream::connect((from_utf8(&domain_name).unwrap(), port))
-                .await
-                .ok()
+            let domain_name = from_utf8(&domain_name).map_err(|_| ConnectError::DnsFailure)?;
+            match resolver.resolve(domain_name).await {
+                Ok(ips) => ips
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, port))
+                    .collect(),
+                Err(ResolveError::Timeout) => return Err(ConnectError::Timeout),
+                Err(_) => return Err(ConnectError::DnsFailure),
+            }
         }
 
-        _ => None,
+        _ => return Err(ConnectError::General),
     };
 
-    let stream = match stream {
-        Some(s) => s,
-        None => return write_port.close().await,
+    if addrs.is_empty() {
+        return Err(ConnectError::DnsFailure);
+    }
+
+    let timeout = Duration::from_millis(CONNECT_TIMEOUT_MS);
+    io::timeout(timeout, TcpStream::connect(&addrs[..]))
+        .await
+        .map_err(|err| ConnectError::from_io_error(&err))
+}
+
+async fn tunnel_port_task_tcp(
+    resolver: &Resolver,
+    msg: TunnelPortMsg,
+    read_port: TunnelReadPort,
+    mut write_port: TunnelWritePort,
+) {
+    let stream = match connect_tcp(resolver, msg).await {
+        Ok(s) => s,
+        Err(err) => {
+            write_port.connect_err(err).await;
+            return write_port.close().await;
+        }
     };
 
     match stream.local_addr() {
@@ -354,144 +823,211 @@ async fn tunnel_port_task_tcp(
         }
     }
 
+    tunnel_port_relay_tcp(stream, read_port, write_port).await;
+}
+
+async fn tunnel_port_relay_tcp(
+    stream: TcpStream,
+    read_port: TunnelReadPort,
+    write_port: TunnelWritePort,
+) {
     let (reader, writer) = &mut (&stream, &stream);
     let w = tunnel_port_write_tcp(reader, write_port);
     let r = tunnel_port_read_tcp(writer, read_port);
     let _ = r.join(w).await;
 }
 
-async fn tcp_tunnel_core_task(key: Vec<u8>, stream: TcpStream) {
+async fn tcp_tunnel_core_task(config: Arc<TunnelConfig>, stream: TcpStream) {
     let (mut main_sender, sub_senders, receivers) = channel_bus(10, 1000);
 
-    let mut port_hub = PortHub::new();
     let (reader, writer) = &mut (&stream, &stream);
+    let remote_addr = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    let handshake = server_handshake(
+        &config.users,
+        config.suite,
+        config.capabilities(),
+        &config.replay_cache,
+        reader,
+        writer,
+    );
+    let (user, session, encryptor, decryptor) = match handshake.await {
+        Ok(handshake) => handshake,
+
+        Err(err) => {
+            error!("TCP tunnel from {} handshake error: {}", remote_addr, err);
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+    };
+
+    let mut port_hub = PortHub::new(session.supports(capability::FLOW_CONTROL));
+
+    info!(
+        "TCP tunnel of {} from {} established, version {}",
+        user, remote_addr, session.version
+    );
+
     let r = async {
-        let _ = process_tunnel_read(key.clone(), &mut main_sender, reader).await;
+        let compression = config.compression(&session);
+        let _ = process_tunnel_read(decryptor, compression, &mut main_sender, reader).await;
         let _ = main_sender.send(TunnelMsg::CloseTunnel).await;
         let _ = stream.shutdown(Shutdown::Both);
     };
     let w = async {
-        let _ =
-            process_tunnel_write(key.clone(), sub_senders, receivers, &mut port_hub, writer).await;
+        let _ = process_tunnel_write(
+            &config,
+            session,
+            encryptor,
+            sub_senders,
+            receivers,
+            &mut port_hub,
+            writer,
+        )
+        .await;
         let _ = stream.shutdown(Shutdown::Both);
     };
     let _ = r.join(w).await;
 
+    info!("TCP tunnel of {} from {} broken", user, remote_addr);
     port_hub.clear_ports();
 }
 
-async fn ucp_tunnel_core_task(key: Vec<u8>, stream: UcpStream) {
+async fn ucp_tunnel_core_task(config: Arc<TunnelConfig>, stream: UcpStream) {
     let (mut main_sender, sub_senders, receivers) = channel_bus(10, 1000);
 
-    let mut port_hub = PortHub::new();
     let (reader, writer) = &mut (&stream, &stream);
+    let remote_addr = stream.remote_addr();
+    let handshake = server_handshake(
+        &config.users,
+        config.suite,
+        config.capabilities(),
+        &config.replay_cache,
+        reader,
+        writer,
+    );
+    let (user, session, encryptor, decryptor) = match handshake.await {
+        Ok(handshake) => handshake,
+
+        Err(err) => {
+            error!("UCP tunnel from {} handshake error: {}", remote_addr, err);
+            stream.shutdown();
+            return;
+        }
+    };
+
+    let mut port_hub = PortHub::new(session.supports(capability::FLOW_CONTROL));
+
+    info!(
+        "UCP tunnel of {} from {} established, version {}",
+        user, remote_addr, session.version
+    );
+    stream.set_identity(&user);
+
     let r = async {
-        let _ = process_tunnel_read(key.clone(), &mut main_sender, reader).await;
+        let compression = config.compression(&session);
+        let _ = process_tunnel_read(decryptor, compression, &mut main_sender, reader).await;
         let _ = main_sender.send(TunnelMsg::CloseTunnel).await;
         stream.shutdown();
     };
     let w = async {
-        let _ =
-            process_tunnel_write(key.clone(), sub_senders, receivers, &mut port_hub, writer).await;
+        let _ = process_tunnel_write(
+            &config,
+            session,
+            encryptor,
+            sub_senders,
+            receivers,
+            &mut port_hub,
+            writer,
+        )
+        .await;
         stream.shutdown();
     };
     let _ = r.join(w).await;
 
+    info!("UCP tunnel of {} from {} broken", user, remote_addr);
     port_hub.clear_ports();
 }
 
 async fn process_tunnel_read<R: Read + Unpin>(
-    key: Vec<u8>,
+    mut decryptor: Cryptor,
+    compression: Option<&CompressionMetrics>,
     sender: &mut MainSender<TunnelMsg>,
     stream: &mut R,
 ) -> std::io::Result<()> {
-    let mut ctr = vec![0; Cryptor::ctr_size()];
-    stream.read_exact(&mut ctr).await?;
-
-    let mut decryptor = Cryptor::with_ctr(&key, ctr);
-
-    let mut buf = vec![0; VERIFY_DATA.len()];
-    stream.read_exact(&mut buf).await?;
-
-    let data = decryptor.decrypt(&buf);
-    if &data != &VERIFY_DATA {
-        return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
-    }
-
     loop {
-        let mut op = [0u8; 1];
-        stream.read_exact(&mut op).await?;
-        let op = op[0];
-
-        if op == cs::HEARTBEAT {
-            let _ = sender.send(TunnelMsg::CSHeartbeat).await;
-            continue;
-        }
-
-        let mut id = [0u8; 4];
-        stream.read_exact(&mut id).await?;
-        let id = u32::from_be(unsafe { *(id.as_ptr() as *const u32) });
-
-        match op {
-            cs::OPEN_PORT => {
-                let _ = sender.send(TunnelMsg::CSOpenPort(id)).await;
+        let frame = match read_cs_frame(stream, &mut decryptor).await {
+            Ok(frame) => frame,
+            Err(FrameError::Io(err)) => return Err(err),
+            Err(err) => {
+                error!("Tunnel recv frame error: {}", err);
+                return Err(err.into());
             }
+        };
 
-            cs::CLOSE_PORT => {
-                let _ = sender.send(TunnelMsg::CSClosePort(id)).await;
+        let msg = match frame {
+            Frame::Heartbeat => TunnelMsg::CSHeartbeat,
+            Frame::OpenPort(id) => TunnelMsg::CSOpenPort(id),
+            Frame::ClosePort(id) => TunnelMsg::CSClosePort(id),
+            Frame::ShutdownWrite(id) => TunnelMsg::CSShutdownWrite(id),
+            Frame::ConnectDomainName(id, domain_name, port) => {
+                TunnelMsg::CSConnectDN(id, domain_name, port)
             }
-
-            cs::SHUTDOWN_WRITE => {
-                let _ = sender.send(TunnelMsg::CSShutdownWrite(id)).await;
+            Frame::Connect(id, data) => TunnelMsg::CSData(cs::CONNECT, id, data),
+            Frame::UdpAssociate(id, data) => TunnelMsg::CSData(cs::UDP_ASSOCIATE, id, data),
+            Frame::Resolve(id, data) => TunnelMsg::CSData(cs::RESOLVE, id, data),
+            Frame::Listen(id, data) => TunnelMsg::CSData(cs::LISTEN, id, data),
+            Frame::Bind(id, data) => TunnelMsg::CSData(cs::BIND, id, data),
+            frame @ Frame::Data(..) | frame @ Frame::CompressedData(..) => {
+                match data_payload(frame, compression) {
+                    Ok((id, data)) => TunnelMsg::CSData(cs::DATA, id, data),
+                    Err(err) => {
+                        error!("Tunnel recv frame error: {}", err);
+                        return Err(err.into());
+                    }
+                }
             }
+            Frame::WindowUpdate(id, credit) => TunnelMsg::CSWindowUpdate(id, credit),
 
-            cs::CONNECT_DOMAIN_NAME => {
-                let mut len = [0u8; 4];
-                stream.read_exact(&mut len).await?;
-                let len = u32::from_be(unsafe { *(len.as_ptr() as *const u32) });
-
-                let mut buf = vec![0; len as usize];
-                stream.read_exact(&mut buf).await?;
-
-                let pos = (len - 2) as usize;
-                let domain_name = decryptor.decrypt(&buf[0..pos]);
-                let port = u16::from_be(unsafe { *(buf[pos..].as_ptr() as *const u16) });
-
-                let _ = sender
-                    .send(TunnelMsg::CSConnectDN(id, domain_name, port))
-                    .await;
+            Frame::Rekey => {
+                decryptor.rekey();
+                TunnelMsg::CSRekey
             }
 
-            _ => {
-                let mut len = [0u8; 4];
-                stream.read_exact(&mut len).await?;
-                let len = u32::from_be(unsafe { *(len.as_ptr() as *const u32) });
-
-                let mut buf = vec![0; len as usize];
-                stream.read_exact(&mut buf).await?;
+            Frame::Padding(_) => continue,
 
-                let data = decryptor.decrypt(&buf);
-                let _ = sender.send(TunnelMsg::CSData(op, id, data)).await;
+            frame => {
+                error!("Tunnel recv unexpected frame: {:?}", frame);
+                return Err(std::io::Error::from(std::io::ErrorKind::InvalidData));
             }
-        }
+        };
+
+        let _ = sender.send(msg).await;
     }
 }
 
 async fn process_tunnel_write<W: Write + Unpin>(
-    key: Vec<u8>,
+    config: &TunnelConfig,
+    session: Session,
+    mut encryptor: Cryptor,
     mut senders: SubSenders<TunnelMsg>,
     receivers: Receivers<TunnelMsg>,
     port_hub: &mut PortHub,
     stream: &mut W,
 ) -> std::io::Result<()> {
     let mut alive_time = Instant::now();
-    let mut encryptor = Cryptor::new(&key);
+    let mut remote_port_id = 0u32;
+    let compression = config.compression(&session);
+    let mut padding = config
+        .padding
+        .filter(|_| session.supports(capability::PADDING))
+        .map(Padding::new);
 
     let duration = Duration::from_millis(HEARTBEAT_INTERVAL_MS);
     let timer_stream = timer::interval(duration, TunnelMsg::Heartbeat);
-    let mut msg_stream = timer_stream.merge(receivers);
-
-    stream.write_all(encryptor.ctr_as_slice()).await?;
+    let mut msg_stream = Scheduler::new(timer_stream.merge(receivers));
 
     loop {
         match msg_stream.next().await {
@@ -504,12 +1040,40 @@ async fn process_tunnel_write<W: Write + Unpin>(
 
             Some(TunnelMsg::CloseTunnel) => break,
 
+            Some(TunnelMsg::CSOpenPort(id)) => {
+                alive_time = Instant::now();
+                open_port(id, config, &mut senders, port_hub);
+            }
+
+            Some(TunnelMsg::SCOpenPort(listen_id, conn)) => {
+                remote_port_id = remote_port_id.wrapping_add(1) & !REMOTE_PORT_ID;
+                let id = REMOTE_PORT_ID | remote_port_id;
+
+                let peer = conn.peer_addr().map(|addr| addr.to_string());
+                let frame = Frame::RemoteOpenPort(id, listen_id, peer.unwrap_or_default().into());
+                let packed_buffer = pack_sc_frame(&frame, &mut encryptor)?;
+                stream.write_all(&packed_buffer).await?;
+
+                let (read_port, write_port) = new_port(id, &mut senders, port_hub);
+                task::spawn(async move {
+                    tunnel_port_relay_tcp(conn, read_port, write_port).await;
+                });
+            }
+
+            // Older clients only learn of the close that follows.
+            Some(TunnelMsg::SCConnectErr(..)) if !session.supports(capability::CONNECT_ERROR) => {}
+
             Some(msg) => {
+                if let (TunnelMsg::SCData(_, ref buf), Some(ref mut padding)) = (&msg, &mut padding)
+                {
+                    padding.add_data(buf.len());
+                }
+
                 process_tunnel_msg(
                     msg,
-                    &mut senders,
                     &mut alive_time,
                     port_hub,
+                    compression,
                     &mut encryptor,
                     stream,
                 )
@@ -518,46 +1082,72 @@ async fn process_tunnel_write<W: Write + Unpin>(
 
             None => break,
         }
+
+        if let Some(len) = padding.as_mut().and_then(|padding| padding.next_padding()) {
+            let packed_buffer = pack_sc_frame(&Frame::Padding(len), &mut encryptor)?;
+            stream.write_all(&packed_buffer).await?;
+        }
     }
 
     Ok(())
 }
 
+fn open_port(
+    id: u32,
+    config: &TunnelConfig,
+    senders: &mut SubSenders<TunnelMsg>,
+    port_hub: &mut PortHub,
+) {
+    let (read_port, write_port) = new_port(id, senders, port_hub);
+
+    let resolver = config.resolver.clone();
+    let remote_forward = config.remote_forward;
+    task::spawn(async move {
+        tunnel_port_task(resolver, remote_forward, read_port, write_port).await;
+    });
+}
+
+fn new_port(
+    id: u32,
+    senders: &mut SubSenders<TunnelMsg>,
+    port_hub: &mut PortHub,
+) -> (TunnelReadPort, TunnelWritePort) {
+    let (tx, rx) = unbounded();
+    let (credit_tx, credit_rx) = unbounded();
+    port_hub.add_port(id, tx, credit_tx);
+
+    let sender = senders.get_one_sender();
+
+    let read_port = TunnelReadPort {
+        id,
+        tx: sender.clone(),
+        rx: Some(rx),
+        consumed: 0,
+    };
+
+    let write_port = TunnelWritePort {
+        id,
+        tx: sender.clone(),
+        credit: 0,
+        credit_rx,
+    };
+
+    (read_port, write_port)
+}
+
 async fn process_tunnel_msg<W: Write + Unpin>(
     msg: TunnelMsg,
-    senders: &mut SubSenders<TunnelMsg>,
     alive_time: &mut Instant,
     port_hub: &mut PortHub,
+    compression: Option<&CompressionMetrics>,
     encryptor: &mut Cryptor,
     stream: &mut W,
 ) -> std::io::Result<()> {
     match msg {
         TunnelMsg::CSHeartbeat => {
             *alive_time = Instant::now();
-            stream.write_all(&pack_sc_heartbeat_rsp_msg()).await?;
-        }
-
-        TunnelMsg::CSOpenPort(id) => {
-            *alive_time = Instant::now();
-            let (tx, rx) = channel(1000);
-            port_hub.add_port(id, tx);
-
-            let sender = senders.get_one_sender();
-
-            let read_port = TunnelReadPort {
-                id: id,
-                tx: sender.clone(),
-                rx: Some(rx),
-            };
-
-            let write_port = TunnelWritePort {
-                id: id,
-                tx: sender.clone(),
-            };
-
-            task::spawn(async move {
-                tunnel_port_task(read_port, write_port).await;
-            });
+            let packed_buffer = pack_sc_frame(&Frame::HeartbeatRsp, encryptor)?;
+            stream.write_all(&packed_buffer).await?;
         }
 
         TunnelMsg::CSClosePort(id) => {
@@ -577,26 +1167,59 @@ async fn process_tunnel_msg<W: Write + Unpin>(
 
         TunnelMsg::CSData(op, id, buf) => {
             *alive_time = Instant::now();
-            port_hub.client_send_data(id, op, buf).await;
+            if !port_hub.client_send_data(id, op, buf).await {
+                error!("Tunnel port {} exceeded window", id);
+                return Err(std::io::Error::from(std::io::ErrorKind::InvalidData));
+            }
+        }
+
+        TunnelMsg::CSWindowUpdate(id, credit) => {
+            *alive_time = Instant::now();
+            port_hub.add_credit(id, credit);
+        }
+
+        TunnelMsg::SCWindowUpdate(id, credit) => {
+            if !port_hub.grant_window(id, credit) {
+                return Ok(());
+            }
+
+            let frame = Frame::WindowUpdate(id, credit);
+            let packed_buffer = pack_sc_frame(&frame, encryptor)?;
+            stream.write_all(&packed_buffer).await?;
+        }
+
+        TunnelMsg::CSRekey => {
+            *alive_time = Instant::now();
+            let packed_buffer = pack_sc_frame(&Frame::Rekey, encryptor)?;
+            stream.write_all(&packed_buffer).await?;
+            encryptor.rekey();
         }
 
         TunnelMsg::SCClosePort(id) => {
             port_hub.server_close_port(id);
-            stream.write_all(&pack_sc_close_port_msg(id)).await?;
+            let packed_buffer = pack_sc_frame(&Frame::ClosePort(id), encryptor)?;
+            stream.write_all(&packed_buffer).await?;
         }
 
         TunnelMsg::SCShutdownWrite(id) => {
-            stream.write_all(&pack_sc_shutdown_write_msg(id)).await?;
+            let packed_buffer = pack_sc_frame(&Frame::ShutdownWrite(id), encryptor)?;
+            stream.write_all(&packed_buffer).await?;
         }
 
         TunnelMsg::SCConnectOk(id, buf) => {
-            let data = encryptor.encrypt(&buf);
-            stream.write_all(&pack_sc_connect_ok_msg(id, &data)).await?;
+            let packed_buffer = pack_sc_frame(&Frame::ConnectOk(id, buf), encryptor)?;
+            stream.write_all(&packed_buffer).await?;
+        }
+
+        TunnelMsg::SCConnectErr(id, err) => {
+            let packed_buffer = pack_sc_frame(&Frame::ConnectErr(id, err), encryptor)?;
+            stream.write_all(&packed_buffer).await?;
         }
 
         TunnelMsg::SCData(id, buf) => {
-            let data = encryptor.encrypt(&buf);
-            stream.write_all(&pack_sc_data_msg(id, &data)).await?;
+            let frame = data_frame(id, buf, compression);
+            let packed_buffer = pack_sc_frame(&frame, encryptor)?;
+            stream.write_all(&packed_buffer).await?;
         }
 
         TunnelMsg::TunnelPortHalfDrop(id) => {
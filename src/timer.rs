@@ -7,9 +7,15 @@ use async_std::stream::Stream;
 use futures_timer::Delay;
 
 pub fn interval<T>(dur: Duration, val: T) -> Interval<T> {
+    jittered_interval(dur, Duration::from_secs(0), val)
+}
+
+// Every tick comes at a random time within `jitter` of the regular one.
+pub fn jittered_interval<T>(dur: Duration, jitter: Duration, val: T) -> Interval<T> {
     Interval {
         delay: Delay::new(dur),
         interval: dur,
+        jitter,
         value: Box::new(val),
     }
 }
@@ -17,6 +23,7 @@ pub fn interval<T>(dur: Duration, val: T) -> Interval<T> {
 pub struct Interval<T> {
     delay: Delay,
     interval: Duration,
+    jitter: Duration,
     value: Box<T>,
 }
 
@@ -28,7 +35,13 @@ impl<T: std::clone::Clone> Stream for Interval<T> {
             return Poll::Pending;
         }
         let when = Instant::now();
-        let next = next_interval(when, Instant::now(), self.interval);
+        let next = if self.jitter.is_zero() {
+            next_interval(when, Instant::now(), self.interval)
+        } else {
+            let jitter_ns = duration_to_nanos(self.jitter * 2).unwrap_or(u64::MAX);
+            let offset = Duration::from_nanos(rand::random::<u64>() % jitter_ns.max(1));
+            when + self.interval.saturating_sub(self.jitter) + offset
+        };
         self.delay.reset(next);
         Poll::Ready(Some(*self.value.clone()))
     }
@@ -8,7 +8,7 @@ use std::cmp::min;
 use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
 use std::time::Instant;
 use std::vec::Vec;
@@ -25,6 +25,7 @@ pub struct UcpStreamMetrics {
     srtt: AtomicU32,
     rttvar: AtomicU32,
     rx_seq: AtomicU32,
+    identity: Mutex<String>,
 }
 
 impl UcpStreamMetrics {
@@ -38,6 +39,7 @@ impl UcpStreamMetrics {
             srtt: AtomicU32::new(0),
             rttvar: AtomicU32::new(0),
             rx_seq: AtomicU32::new(0),
+            identity: Mutex::new(String::new()),
         }
     }
 
@@ -72,6 +74,10 @@ impl UcpStreamMetrics {
     pub fn get_rx_seq(&self) -> u32 {
         self.rx_seq.load(Ordering::Relaxed)
     }
+
+    pub fn get_identity(&self) -> String {
+        self.identity.lock().unwrap().clone()
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -251,6 +257,14 @@ impl InnerStream {
         self.die();
     }
 
+    pub(super) fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub(super) fn set_identity(&self, identity: &str) {
+        *self.metrics.identity.lock().unwrap() = identity.to_string();
+    }
+
     pub(super) fn alive(&self) -> bool {
         self.alive.load(Ordering::Relaxed)
     }
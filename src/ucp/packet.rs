@@ -92,10 +92,16 @@ impl UcpPacket {
     }
 
     pub(super) fn parse_u32(&self, offset: &mut isize) -> u32 {
-        let u = unsafe { *(self.buf.as_ptr().offset(*offset) as *const u32) };
+        let pos = *offset as usize;
+        let u = [
+            self.buf[pos],
+            self.buf[pos + 1],
+            self.buf[pos + 2],
+            self.buf[pos + 3],
+        ];
 
         *offset += 4;
-        u32::from_be(u)
+        u32::from_be_bytes(u)
     }
 
     pub(super) fn parse_u8(&self, offset: &mut isize) -> u8 {
@@ -105,9 +111,8 @@ impl UcpPacket {
     }
 
     pub(super) fn write_u32(&mut self, offset: &mut isize, u: u32) {
-        unsafe {
-            *(self.buf.as_ptr().offset(*offset) as *mut u32) = u.to_be();
-        }
+        let pos = *offset as usize;
+        self.buf[pos..pos + 4].copy_from_slice(&u.to_be_bytes());
 
         *offset += 4;
     }
@@ -2,7 +2,7 @@ use async_std::io::{self, Read, Write};
 use async_std::net::UdpSocket;
 use async_std::task;
 
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -20,8 +20,12 @@ pub struct UcpStream {
 
 impl UcpStream {
     pub async fn connect(server_addr: &str, metrics: Arc<UcpStreamMetrics>) -> Self {
-        let socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await.unwrap());
         let remote_addr = SocketAddr::from_str(server_addr).unwrap();
+        let local_addr = match remote_addr {
+            SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        };
+        let socket = Arc::new(UdpSocket::bind(local_addr).await.unwrap());
 
         let inner = Arc::new(InnerStream::new(socket, remote_addr, metrics));
         inner.connecting();
@@ -43,6 +47,15 @@ impl UcpStream {
         self.inner.shutdown();
     }
 
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.inner.remote_addr()
+    }
+
+    // Tags the metrics of this stream, e.g. with the user of the tunnel.
+    pub fn set_identity(&self, identity: &str) {
+        self.inner.set_identity(identity);
+    }
+
     pub(super) async fn send(inner: Arc<InnerStream>) {
         loop {
             task::sleep(Duration::from_millis(10)).await;